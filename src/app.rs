@@ -1,5 +1,8 @@
+use std::collections::HashSet;
 use std::ffi::OsStr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Mutex;
 
 use anyhow::{bail, Context};
 use chumsky::container::Seq;
@@ -8,14 +11,24 @@ use dialoguer::MultiSelect;
 use owo_colors::OwoColorize;
 use path_clean::PathClean;
 use pathdiff::diff_paths;
+use saphyr::Yaml;
 use walkdir::WalkDir;
 
-use crate::options::{Frontend, Options};
-use crate::spec::Spec;
+use crate::bundle::{self, Checkout, Lock, Locked};
+use crate::config::{Config, Signatures};
+use crate::fetcher::{self, short, Backend};
+use crate::hooks::Hook;
+use crate::options::{BundleCommand, CacheCommand, Command, Frontend, Options};
+use crate::package::Package;
+use crate::progress::{Log, Progress};
+use crate::spec::{checkout_path, split_url, unescape_path, Origin, Recipe, Spec, RECIPES_DIR};
+use crate::state::State;
 use crate::Result;
 
 pub struct App {
     options: Options,
+    config: Config,
+    state: State,
     rime_dir: PathBuf,
     data_dir: PathBuf,
     packages_dir: PathBuf,
@@ -25,37 +38,71 @@ impl App {
     pub fn new(options: Options) -> Self {
         Self {
             options,
+            config: Config::default(),
+            state: State::default(),
             rime_dir: PathBuf::new(),
             data_dir: PathBuf::new(),
             packages_dir: PathBuf::new(),
         }
     }
 
-    pub fn run(mut self) -> Result {
+    pub fn run(mut self) -> Result<ExitCode> {
         self.initialize()?;
 
         if self.options.list {
-            return self.list();
+            self.list()?;
+            return Ok(ExitCode::SUCCESS);
         }
 
-        self.banner();
+        match &self.options.command {
+            Some(Command::Outdated) => return self.outdated(),
+            Some(Command::Cache { command }) => self.cache(command.clone())?,
+            Some(Command::Bundle {
+                command: BundleCommand::Create { output, targets },
+            }) => self.bundle_create(&output.clone(), &targets.clone())?,
+            Some(Command::Bundle {
+                command: BundleCommand::Install { file },
+            }) => self.bundle_install(&file.clone())?,
+            Some(Command::Update) => {
+                self.banner();
+                self.update()?;
+            }
+            Some(Command::Rollback { target }) => {
+                self.banner();
+                self.rollback(&target.clone())?;
+            }
+            None => {
+                self.banner();
+                let mut specs = self.resolve()?;
 
-        let mut specs = self.resolve()?;
+                if self.options.select {
+                    // Cancelled by the user
+                    let Some(selected) = self.select(specs) else {
+                        return Ok(ExitCode::FAILURE);
+                    };
+                    if selected.is_empty() {
+                        bail!("no package is selected")
+                    }
+                    specs = selected;
+                }
 
-        if self.options.select {
-            specs = self.select(specs);
-            if specs.is_empty() {
-                bail!("no package is selected")
+                self.install(specs)?;
             }
         }
 
-        self.install(specs)
+        Ok(ExitCode::SUCCESS)
     }
 
     fn initialize(&mut self) -> Result {
         self.rime_dir = Self::find_rime_dir(&self.options)?;
         self.data_dir = Self::find_data_dir()?;
         self.packages_dir = self.data_dir.join("packages");
+        self.config = Config::load(&Self::find_config_file(&self.options)?)?;
+        if let Some(local_changes) = self.options.local_changes {
+            self.config.fetcher.local_changes = local_changes;
+        }
+        self.config.fetcher.network.offline = self.options.offline;
+        self.state = State::load(&self.data_dir.join("state.yaml"))?;
 
         std::fs::create_dir_all(&self.rime_dir)?;
         std::fs::create_dir_all(&self.data_dir)?;
@@ -65,19 +112,10 @@ impl App {
     }
 
     fn list(&self) -> Result {
-        for entry in WalkDir::new(&self.packages_dir)
-            .min_depth(2)
-            .max_depth(2)
-            .into_iter()
-            .filter_map(|x| x.ok())
-            .filter(|x| x.file_type().is_dir())
-        {
-            let repo_path = entry.path();
-            let repo = diff_paths(repo_path, &self.packages_dir)
-                .context("walked path shouldn't be relative")?
-                .to_string_lossy()
-                .into_owned();
-
+        for repo in self.cached_repos()? {
+            let repo_path = &self
+                .packages_dir
+                .join(repo.parse::<Spec>()?.checkout_path());
             let repo = repo.strip_prefix("rime/rime-").unwrap_or(&repo);
             println!("{repo}");
 
@@ -102,6 +140,241 @@ impl App {
         Ok(())
     }
 
+    /// Checks every cached package, reporting those that cannot be checked and going on
+    /// with the others. Exits with 1 if any cannot be checked, or 100 if any is outdated.
+    fn outdated(&self) -> Result<ExitCode> {
+        let mut outdated = 0;
+        let mut failed = 0;
+
+        for repo in self.cached_repos()? {
+            let (local, remote) = match self.check_outdated(&repo) {
+                Ok(x) => x,
+                Err(e) => {
+                    eprintln!("{} {repo}: {:#}", "Failed:".red(), e);
+                    failed += 1;
+                    continue;
+                }
+            };
+
+            if local != remote {
+                println!(
+                    "{} {repo} {} → {}",
+                    "Outdated:".yellow(),
+                    short(&local),
+                    short(&remote)
+                );
+                outdated += 1;
+            }
+        }
+
+        if outdated == 0 && failed == 0 {
+            println!("All packages are up to date");
+            return Ok(ExitCode::SUCCESS);
+        }
+
+        println!();
+        if outdated > 0 {
+            println!("{outdated} package(s) can be updated");
+        }
+        if failed > 0 {
+            println!("{failed} package(s) could not be checked");
+            return Ok(ExitCode::FAILURE);
+        }
+
+        Ok(ExitCode::from(100))
+    }
+
+    /// Returns the cached and the latest upstream commits of a cached repository.
+    fn check_outdated(&self, repo: &str) -> Result<(String, String)> {
+        let spec = repo.parse::<Spec>()?;
+        let package = self.package(&spec)?;
+
+        let local = package
+            .commit()
+            .context("failed to read the cached revision")?;
+        let remote = package
+            .remote_commit()
+            .context("failed to check the upstream revision")?;
+
+        Ok((local, remote))
+    }
+
+    /// Returns the cached repositories, with the branches of non-default checkouts.
+    fn cached_repos(&self) -> Result<Vec<String>> {
+        WalkDir::new(&self.packages_dir)
+            .min_depth(2)
+            .max_depth(2)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_entry(|x| !x.path().starts_with(self.packages_dir.join(RECIPES_DIR)))
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_dir())
+            .map(|entry| {
+                let repo = diff_paths(entry.path(), &self.packages_dir)
+                    .context("walked path shouldn't be relative")?;
+                Ok(unescape_path(&repo.to_string_lossy()))
+            })
+            .try_collect()
+    }
+
+    /// Returns the cached repositories and the URL directories of the downloaded recipe
+    /// files, with their paths relative to the packages directory.
+    fn cached_paths(&self) -> Result<Vec<(String, PathBuf)>> {
+        let mut paths = Vec::new();
+        for repo in self.cached_repos()? {
+            let path = repo.parse::<Spec>()?.checkout_path();
+            paths.push((repo, path));
+        }
+
+        let recipes = WalkDir::new(self.packages_dir.join(RECIPES_DIR))
+            .min_depth(1)
+            .max_depth(1)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|x| x.ok())
+            .filter(|x| x.file_type().is_dir());
+
+        for entry in recipes {
+            let path = Path::new(RECIPES_DIR).join(entry.file_name());
+            let name = match self.checkouts_at(&path).into_iter().next() {
+                Some(checkout) => checkout,
+                // Left without the scheme if neither fetched nor installed
+                None => unescape_path(&entry.file_name().to_string_lossy()),
+            };
+            paths.push((name, path));
+        }
+
+        Ok(paths)
+    }
+
+    /// Returns the checkouts fetched or installed at the path relative to the packages
+    /// directory, as the escaped paths of recipe files have lost the scheme of the URLs.
+    fn checkouts_at(&self, path: &Path) -> Vec<String> {
+        let installed = self
+            .state
+            .installs()
+            .filter_map(|x| x.spec().ok())
+            .filter_map(|spec| match spec.recipe() {
+                Some(Recipe::File(file)) => file.rsplit_once('/').map(|x| x.0.to_string()),
+                _ => Some(spec.checkout()),
+            });
+
+        let mut checkouts = self
+            .state
+            .fetched()
+            .map(String::from)
+            .chain(installed)
+            .filter(|x| checkout_path(x).as_deref() == Some(path))
+            .collect::<Vec<_>>();
+        checkouts.sort_unstable();
+        checkouts.dedup();
+        checkouts
+    }
+
+    fn cache(&mut self, command: CacheCommand) -> Result {
+        match command {
+            CacheCommand::Size => self.cache_size(),
+            CacheCommand::Prune { dry_run } => self.cache_remove(false, dry_run),
+            CacheCommand::Clear { dry_run } => self.cache_remove(true, dry_run),
+        }
+    }
+
+    fn cache_size(&self) -> Result {
+        let installed = self.installed_paths()?;
+        let mut total = 0;
+
+        for (repo, path) in self.cached_paths()? {
+            let size = dir_size(&self.packages_dir.join(&path));
+            total += size;
+
+            let note = match installed.contains(&path) {
+                true => "",
+                false => " (not installed)",
+            };
+            println!("{:>10}  {repo}{}", human_size(size), note.dimmed());
+        }
+
+        println!();
+        println!("{} {}", "Total:".cyan(), human_size(total));
+        Ok(())
+    }
+
+    /// Removes the cached packages, only those not installed anywhere unless `all`.
+    fn cache_remove(&mut self, all: bool, dry_run: bool) -> Result {
+        let installed = self.installed_paths()?;
+        let label = match dry_run {
+            true => "Would remove:",
+            false => "Removed:",
+        };
+
+        let mut total = 0;
+        let mut removed = 0;
+        for (repo, path) in self.cached_paths()? {
+            if !all && installed.contains(&path) {
+                continue;
+            }
+
+            let dir = self.packages_dir.join(&path);
+            let size = dir_size(&dir);
+            total += size;
+            removed += 1;
+
+            if !dry_run {
+                std::fs::remove_dir_all(&dir)
+                    .with_context(|| format!("failed to remove {}", dir.display()))?;
+                // Also remove the directory of the owner if it is left empty
+                if let Some(parent) = dir.parent() {
+                    std::fs::remove_dir(parent).ok();
+                }
+                for checkout in self.checkouts_at(&path) {
+                    self.state.unmark_fetched(&checkout);
+                }
+            }
+
+            println!("{} {repo} ({})", label.yellow(), human_size(size));
+        }
+
+        if removed == 0 {
+            println!("Nothing to remove");
+        } else {
+            let label = match dry_run {
+                true => "Would free:",
+                false => "Freed:",
+            };
+            println!();
+            println!("{} {}", label.cyan(), human_size(total));
+        }
+
+        if !dry_run {
+            self.state.save()?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the paths of the checkouts and the downloaded recipe files of the packages
+    /// installed into any RIME directory.
+    fn installed_paths(&self) -> Result<HashSet<PathBuf>> {
+        let mut paths = HashSet::new();
+
+        for install in self.state.installs() {
+            let spec = install.spec()?;
+            if let Some(recipe @ Recipe::File(file)) = spec.recipe() {
+                if split_url(file).is_some() {
+                    let path = recipe.path(Path::new(""), Path::new(""));
+                    paths.extend(path.parent().map(|x| x.to_path_buf()));
+                }
+            }
+            paths.insert(spec.checkout_path());
+        }
+
+        Ok(paths)
+    }
+
+    fn package<'a>(&self, spec: &'a Spec) -> Result<Package<'a>> {
+        spec.locate_package(&self.packages_dir, &self.config.fetcher)
+    }
+
     fn banner(&self) {
         let frontend = match self.options.dir {
             Some(_) => Frontend::Unknown,
@@ -127,31 +400,562 @@ impl App {
         Ok(resolved.concat())
     }
 
-    fn select(&self, candidates: Vec<Spec>) -> Vec<Spec> {
+    /// Returns the packages picked by the user, or `None` if cancelled.
+    fn select(&self, candidates: Vec<Spec>) -> Option<Vec<Spec>> {
         let choices = MultiSelect::with_theme(&SimpleTheme)
             .with_prompt("Pick the packages to be installed, or press Ctrl+C to cancel")
             .items(&candidates)
             .interact()
-            .unwrap_or_else(|_| std::process::exit(1));
+            .ok()?;
 
-        candidates
+        let selected = candidates
             .into_iter()
             .enumerate()
             .filter(|(i, _)| choices.contains(i))
             .map(|(_, spec)| spec)
-            .collect()
+            .collect();
+
+        Some(selected)
+    }
+
+    fn update(&mut self) -> Result {
+        let specs = self
+            .state
+            .installs_in(&self.rime_dir)
+            .map(|x| x.spec())
+            .try_collect::<Vec<_>>()?;
+
+        if specs.is_empty() {
+            bail!("no package has been installed into the RIME directory");
+        }
+
+        self.install(specs)
+    }
+
+    fn install(&mut self, specs: Vec<Spec>) -> Result {
+        let specs = self.load_recipes(specs, !self.options.offline)?;
+        self.check_trusted(&specs)?;
+
+        let names = specs.iter().map(|x| x.name()).collect::<Vec<_>>().join(" ");
+
+        Hook::new("pre_fetch", &self.config.hooks.pre_fetch, &self.rime_dir)
+            .env("RIMEKA_PACKAGES", &names)
+            .run()?;
+
+        self.fetch_all(&specs)?;
+
+        for spec in &specs {
+            self.install_package(spec)?;
+        }
+
+        Hook::new("post_run", &self.config.hooks.post_run, &self.rime_dir)
+            .env("RIMEKA_PACKAGES", &names)
+            .run()
+    }
+
+    /// Fetches the stale repositories, listing the changes of each spec, and narrows
+    /// the sparse checkouts to the files to be installed.
+    fn fetch_all(&mut self, specs: &[Spec]) -> Result {
+        let repos = self.stale(repositories(specs))?;
+        let befores = self.fetch(specs, &repos)?;
+
+        for repo in &repos {
+            self.state.mark_fetched(&repo.checkout());
+        }
+        self.state.save()?;
+
+        for (spec, before) in specs.iter().zip(befores) {
+            if !repos.iter().any(|x| x.checkout() == spec.checkout()) {
+                continue;
+            }
+
+            println!("{} {}", "Fetched:".green(), spec.target());
+            let package = self.package(spec)?;
+            let after = package.commit()?;
+            match before {
+                Some(before) if before != after => {
+                    println!(
+                        "- {} {} → {}",
+                        "Updated:".cyan(),
+                        short(&before),
+                        short(&after)
+                    );
+                    self.changelog(&package, &before, &after)?;
+                }
+                Some(_) => println!("- {} {}", "Up to date:".cyan(), short(&after)),
+                None => {}
+            }
+        }
+
+        if self.config.fetcher.sparse {
+            for repo in repositories(specs) {
+                self.narrow(repo, specs)?;
+            }
+        }
+
+        Ok(())
     }
 
-    fn install(&self, specs: Vec<Spec>) -> Result {
+    fn bundle_create(&mut self, output: &Path, targets: &[String]) -> Result {
+        let specs = targets
+            .iter()
+            .map(|x| Spec::resolve(x))
+            .try_collect::<Vec<_>>()?
+            .concat();
+        let specs = self.load_recipes(specs, !self.options.offline)?;
+
         for spec in &specs {
-            println!("{} {}", "Fetching:".green(), spec.repo(),);
-            spec.locate_package(&self.packages_dir).fetch()?;
+            if let Some(Recipe::File(file)) = spec.recipe() {
+                if split_url(file).is_none() {
+                    bail!("{file} is a local file, which cannot be bundled");
+                }
+            }
         }
 
+        self.check_trusted(&specs)?;
+        self.fetch_all(&specs)?;
+
+        let mut lock = Lock::default();
+        let mut checkouts = Vec::<Checkout>::new();
+
         for spec in &specs {
-            println!("{} {}", "Installing:".green(), spec.name());
-            spec.locate_package(&self.packages_dir)
-                .install(self.rime_dir.clone())?;
+            println!("{} {}", "Bundling:".green(), spec.name());
+            let package = self.package(spec)?;
+
+            let signatures = &self.config.signatures;
+            let verified = requires_signature(signatures, spec)?;
+            if verified {
+                package
+                    .verify(signatures)
+                    .context("failed to verify the signature")?;
+                println!("- {} verified", "Signature:".cyan());
+            }
+
+            package.download()?;
+
+            let commit = package.commit()?;
+            println!("- {} {}", "Revision:".cyan(), short(&commit));
+
+            lock.packages.push(Locked {
+                target: spec.target(),
+                commit: commit.clone(),
+                verified,
+            });
+
+            let path = spec.checkout_path();
+            if !checkouts.iter().any(|x| x.path == path) {
+                checkouts.push(Checkout {
+                    path,
+                    dir: package.dir().to_path_buf(),
+                    commit,
+                });
+            }
+
+            // The recipe file of a repository is bundled as if it were installed alone
+            if let Some(Recipe::File(file)) = spec.recipe() {
+                let recipe = file.parse::<Spec>()?;
+                let path = recipe.checkout_path();
+                if spec.origin() == Origin::Repository && !checkouts.iter().any(|x| x.path == path)
+                {
+                    let package = self.package(&recipe)?;
+                    checkouts.push(Checkout {
+                        path,
+                        dir: package.dir().to_path_buf(),
+                        commit: package.commit()?,
+                    });
+                }
+            }
+        }
+
+        bundle::create(output, &checkouts, &lock)?;
+
+        let size = std::fs::metadata(output)?.len();
+        println!();
+        println!(
+            "{} {} ({})",
+            "Created:".green(),
+            output.display(),
+            human_size(size)
+        );
+
+        Ok(())
+    }
+
+    /// Installs the packages of a bundle as they are, never accessing the network.
+    fn bundle_install(&mut self, file: &Path) -> Result {
+        // Removed when dropped, whether the installation succeeds or not
+        let dir = tempfile::Builder::new()
+            .prefix("bundle-")
+            .tempdir_in(&self.data_dir)
+            .context("failed to create a temporary directory")?;
+
+        let lock = bundle::extract(file, dir.path())
+            .with_context(|| format!("failed to extract {}", file.display()))?;
+
+        // The checkouts are read like those of the archive backend, without git
+        self.packages_dir = bundle::packages_dir(dir.path());
+        self.banner();
+        self.options.offline = true;
+        let fetcher = &mut self.config.fetcher;
+        fetcher.backend = Backend::Archive;
+        fetcher.hosts.clear();
+        fetcher.packages.clear();
+        fetcher.sparse = false;
+        fetcher.network.offline = true;
+
+        let mut specs = Vec::new();
+        for Locked {
+            target,
+            commit,
+            verified,
+        } in &lock.packages
+        {
+            let spec = target.parse::<Spec>()?;
+            if self.package(&spec)?.commit().ok().as_ref() != Some(commit) {
+                bail!("{target} in the bundle does not match the lock file");
+            }
+            if requires_signature(&self.config.signatures, &spec)? && !verified {
+                bail!("{target} in the bundle is not verified, which `signatures` requires");
+            }
+            specs.push(spec);
+        }
+
+        if specs.is_empty() {
+            bail!("no package in the bundle");
+        }
+
+        // The signatures were verified when creating the bundle
+        self.config.signatures = Default::default();
+
+        self.install(specs)
+    }
+
+    /// Downloads the recipe files of URLs, or uses the cached ones if `download` is
+    /// false, and moves those declaring a `source` onto the repository.
+    fn load_recipes(&self, specs: Vec<Spec>, download: bool) -> Result<Vec<Spec>> {
+        specs
+            .into_iter()
+            .map(|spec| {
+                let Some(recipe @ Recipe::File(file)) = spec.recipe() else {
+                    return Ok(spec);
+                };
+
+                let package = self.package(&spec)?;
+                let path = package.recipe_path(recipe);
+
+                // Recipe files installed alone are downloaded again when fetched
+                let standalone = spec.origin() != Origin::Repository;
+                if download && split_url(file).is_some() && !(standalone && path.is_file()) {
+                    // Checked before contacting the host, and again with the `source` of
+                    // the recipe once it is parsed
+                    if !self.options.allow_untrusted {
+                        self.check_trusted(std::slice::from_ref(&spec))?;
+                    }
+
+                    println!("{} {}", "Downloading:".green(), file);
+                    let urls = package.mirrors().urls(file);
+                    fetcher::download(&Log::Stdout, &urls, package.network(), &path)
+                        .with_context(|| format!("failed to download {file}"))?;
+
+                    // Recipe files installed alone are recorded when fetched with their files
+                    if !standalone {
+                        if let Some(dir) = path.parent() {
+                            fetcher::record_revision(dir)?;
+                        }
+                    }
+                }
+
+                if !standalone || !path.is_file() {
+                    return Ok(spec);
+                }
+
+                let yaml = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read {file}"))?;
+                let docs = Yaml::load_from_str(&yaml)
+                    .with_context(|| format!("failed to parse {file}"))?;
+                let Some(source) = docs.first().and_then(|x| x["recipe"]["source"].as_str()) else {
+                    return Ok(spec);
+                };
+
+                let file = file.clone();
+                source
+                    .parse()
+                    .and_then(|x| spec.with_source(x))
+                    .with_context(|| format!("invalid `source` in {file}"))
+            })
+            .try_collect()
+    }
+
+    /// Returns the repository and the recipe file of the spec not allowed by `trusted`
+    /// in the config file. Recipe files on the local disk are always allowed.
+    fn untrusted<'a>(&self, spec: &'a Spec) -> Vec<&'a str> {
+        let trusted = &self.config.trusted;
+        let mut untrusted = Vec::new();
+
+        if spec.origin() == Origin::Repository && !trusted.allows(spec.host(), spec.repo()) {
+            untrusted.push(spec.repo());
+        }
+
+        if let Some(Recipe::File(file)) = spec.recipe() {
+            if split_url(file).is_some_and(|(host, path)| !trusted.allows(host, path)) {
+                untrusted.push(file.as_str());
+            }
+        }
+
+        untrusted
+    }
+
+    /// Refuses the sources not allowed by `trusted` in the config file, unless
+    /// `--allow-untrusted` is given.
+    fn check_trusted(&self, specs: &[Spec]) -> Result {
+        let untrusted = specs
+            .iter()
+            .filter(|x| !self.untrusted(x).is_empty())
+            .collect::<Vec<_>>();
+
+        if untrusted.is_empty() {
+            return Ok(());
+        }
+
+        if !self.options.allow_untrusted {
+            let mut repos = untrusted
+                .iter()
+                .flat_map(|x| self.untrusted(x))
+                .collect::<Vec<_>>();
+            repos.sort_unstable();
+            repos.dedup();
+            bail!(
+                "untrusted source(s): {}; add them to `trusted` in the config file, or \
+                 install with --allow-untrusted",
+                repos.join(", ")
+            );
+        }
+
+        for spec in untrusted {
+            println!("{} {}", "Untrusted:".yellow(), spec.name());
+        }
+
+        Ok(())
+    }
+
+    /// Returns the repositories to be fetched, skipping the cached ones when offline or
+    /// fetched within the TTL.
+    fn stale<'a>(&self, repos: Vec<&'a Spec>) -> Result<Vec<&'a Spec>> {
+        let mut stale = Vec::new();
+
+        for spec in repos {
+            let fresh = self
+                .state
+                .fetched_since(&spec.checkout())
+                .is_some_and(|x| x < self.config.fetcher.ttl);
+
+            if !self.options.offline && !fresh {
+                stale.push(spec);
+                continue;
+            }
+
+            let Ok(commit) = self.package(spec)?.commit() else {
+                if self.options.offline {
+                    bail!(
+                        "{} has never been fetched, which --offline requires",
+                        spec.checkout()
+                    );
+                }
+
+                stale.push(spec);
+                continue;
+            };
+
+            let reason = match self.options.offline {
+                true => "offline",
+                false => "fetched recently",
+            };
+
+            println!(
+                "{} {} at {} ({})",
+                "Cached:".green(),
+                spec.checkout(),
+                short(&commit),
+                reason
+            );
+        }
+
+        Ok(stale)
+    }
+
+    /// Fetches each repository once with up to `--jobs` repositories at a time,
+    /// returning the commits of the specs installed or cached before fetching.
+    fn fetch(&self, specs: &[Spec], repos: &[&Spec]) -> Result<Vec<Option<String>>> {
+        let befores = specs
+            .iter()
+            .map(|spec| self.previous_commit(spec))
+            .try_collect::<Vec<_>>()?;
+
+        let jobs = self.options.jobs.clamp(1, repos.len().max(1));
+        let progress = Progress::new();
+        let queue = Mutex::new(repos.iter().enumerate());
+        let results = Mutex::new(repos.iter().map(|_| None).collect::<Vec<_>>());
+
+        std::thread::scope(|s| {
+            for _ in 0..jobs {
+                s.spawn(|| loop {
+                    let Some((i, spec)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+
+                    let task = progress.task(&spec.checkout());
+                    let result = self.fetch_package(spec, task.log());
+                    task.finish(result.is_ok());
+                    results.lock().unwrap()[i] = Some(result);
+                });
+            }
+        });
+
+        println!();
+
+        for (spec, result) in repos.iter().zip(results.into_inner().unwrap()) {
+            if let Some(result) = result {
+                result.with_context(|| format!("failed to fetch {}", spec.checkout()))?;
+            }
+        }
+
+        Ok(befores)
+    }
+
+    /// Returns the commit installed by the spec, or the cached commit if not installed.
+    fn previous_commit(&self, spec: &Spec) -> Result<Option<String>> {
+        match self.state.find(&self.rime_dir, spec) {
+            Some(install) => Ok(Some(install.commit.clone())),
+            None => Ok(self.package(spec)?.commit().ok()),
+        }
+    }
+
+    fn fetch_package(&self, spec: &Spec, log: &Log) -> Result {
+        let package = self.package(spec)?;
+        match package.url() {
+            Some(url) => log.println(format!(
+                "{} {} ({})",
+                "Fetching:".green(),
+                spec.checkout(),
+                url
+            )),
+            None => log.println(format!("{} {}", "Fetching:".green(), spec.checkout())),
+        }
+
+        package.fetch(log)
+    }
+
+    fn rollback(&mut self, target: &str) -> Result {
+        let mut specs = self.load_recipes(vec![target.parse()?], false)?;
+        let spec = specs.remove(0);
+        let install = self
+            .state
+            .find(&self.rime_dir, &spec)
+            .context("the package has not been installed into the RIME directory")?;
+
+        let spec = install.spec()?;
+        self.check_trusted(std::slice::from_ref(&spec))?;
+
+        let current = install.commit.clone();
+        let Some(previous) = self.state.rollback(&self.rime_dir, &spec) else {
+            bail!("no previously installed revision of {}", spec.name());
+        };
+
+        println!("{} {}", "Rolling back:".green(), spec.checkout());
+        println!(
+            "- {} {} → {}",
+            "Revision:".cyan(),
+            short(&current),
+            short(&previous)
+        );
+        self.package(&spec)?.checkout(&previous)?;
+        if self.config.fetcher.sparse {
+            self.narrow(&spec, std::slice::from_ref(&spec))?;
+        }
+
+        self.install_package(&spec)?;
+
+        Hook::new("post_run", &self.config.hooks.post_run, &self.rime_dir)
+            .env("RIMEKA_PACKAGES", spec.name())
+            .run()
+    }
+
+    /// Checks out the files of a sparse checkout that the specs sharing it may install,
+    /// along with those of the installed ones to keep them updatable.
+    fn narrow(&self, repo: &Spec, specs: &[Spec]) -> Result {
+        let installed = self
+            .state
+            .installs()
+            .filter_map(|x| x.spec().ok())
+            .collect::<Vec<_>>();
+
+        let mut patterns = Vec::new();
+        for spec in specs.iter().chain(&installed) {
+            if spec.checkout() != repo.checkout() {
+                continue;
+            }
+
+            // Broken recipes are left to be reported when installing
+            if let Ok((include, _)) = self.package(spec)?.patterns() {
+                patterns.extend(include);
+            }
+        }
+
+        self.package(repo)?
+            .narrow(&Log::Stdout, &patterns)
+            .with_context(|| format!("failed to check out the files of {}", repo.checkout()))
+    }
+
+    fn install_package(&mut self, spec: &Spec) -> Result {
+        println!("{} {}", "Installing:".green(), spec.name());
+        let package = self.package(spec)?;
+
+        let signatures = &self.config.signatures;
+        if requires_signature(signatures, spec)? {
+            package
+                .verify(signatures)
+                .context("failed to verify the signature")?;
+            println!("- {} verified", "Signature:".cyan());
+        }
+
+        package.install(self.rime_dir.clone())?;
+
+        let commit = package.commit()?;
+        let limit = self.config.history_limit;
+        self.state
+            .record(&self.rime_dir, spec, commit.clone(), limit);
+        self.state.save()?;
+
+        Hook::new(
+            "post_install",
+            &self.config.hooks.post_install,
+            &self.rime_dir,
+        )
+        .env("RIMEKA_PACKAGE", spec.name())
+        .env("RIMEKA_PACKAGE_DIR", package.dir().to_string_lossy())
+        .env("RIMEKA_COMMIT", commit)
+        .env("RIMEKA_OPTIONS", spec.options_string())
+        .run()
+    }
+
+    fn changelog(&self, package: &Package, from: &str, to: &str) -> Result {
+        const MAX_COMMITS: usize = 20;
+
+        let Some(changes) = package.changes(from, to)? else {
+            println!("  (history unavailable)");
+            return Ok(());
+        };
+
+        for line in changes.log.iter().take(MAX_COMMITS) {
+            println!("  {line}");
+        }
+
+        if changes.log.len() > MAX_COMMITS {
+            println!("  ... and {} more", changes.log.len() - MAX_COMMITS);
+        }
+
+        for file in &changes.files {
+            println!("- {} {}", "Changed:".cyan(), file);
         }
 
         Ok(())
@@ -180,7 +984,19 @@ impl App {
 
     fn find_data_dir() -> Result<PathBuf> {
         let data = dirs::data_local_dir().context("user profile dir unavailable")?;
+        Ok(data.join(Self::dir_name()?))
+    }
+
+    fn find_config_file(options: &Options) -> Result<PathBuf> {
+        if let Some(x) = &options.config {
+            return Ok(x.clean());
+        }
 
+        let config = dirs::config_dir().context("user profile dir unavailable")?;
+        Ok(config.join(Self::dir_name()?).join("config.yaml"))
+    }
+
+    fn dir_name() -> Result<&'static str> {
         #[allow(unreachable_patterns)]
         let name = match true {
             cfg!(target_os = "windows") => "Rimeka",
@@ -189,6 +1005,165 @@ impl App {
             _ => bail!("unsupported operating system"),
         };
 
-        Ok(data.join(name))
+        Ok(name)
+    }
+}
+
+/// Returns whether the revision of the spec must be verified, refusing a recipe file
+/// from outside the repository, which the signature does not cover.
+fn requires_signature(signatures: &Signatures, spec: &Spec) -> Result<bool> {
+    if !signatures.is_required(spec.repo()) {
+        return Ok(false);
+    }
+
+    if let Some(Recipe::File(file)) = spec.recipe() {
+        bail!(
+            "{file} is not signed along with {}, which `signatures` requires",
+            spec.repo()
+        );
+    }
+
+    Ok(true)
+}
+
+/// Returns a spec of each checkout to be fetched, as specs of the same repository and
+/// branch share a checkout.
+fn repositories(specs: &[Spec]) -> Vec<&Spec> {
+    let mut repos = Vec::<&Spec>::new();
+
+    for spec in specs {
+        if !repos.iter().any(|x| x.checkout() == spec.checkout()) {
+            repos.push(spec);
+        }
+    }
+
+    repos
+}
+
+fn dir_size(dir: &Path) -> u64 {
+    WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter_map(|x| x.metadata().ok())
+        .filter(|x| x.is_file())
+        .map(|x| x.len())
+        .sum()
+}
+
+fn human_size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = None;
+
+    for x in units {
+        if size < 1024.0 {
+            break;
+        }
+        size /= 1024.0;
+        unit = Some(x);
+    }
+
+    match unit {
+        Some(unit) => format!("{size:.1} {unit}"),
+        None => format!("{bytes} B"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app(dir: &Path) -> App {
+        let options = Options {
+            list: false,
+            select: false,
+            frontend: Frontend::Unknown,
+            dir: None,
+            config: None,
+            jobs: 1,
+            offline: false,
+            local_changes: None,
+            allow_untrusted: false,
+            command: None,
+            targets: Vec::new(),
+        };
+
+        App {
+            options,
+            config: Config::default(),
+            state: State::load(&dir.join("state.yaml")).unwrap(),
+            rime_dir: dir.join("rime"),
+            data_dir: dir.to_path_buf(),
+            packages_dir: dir.join("packages"),
+        }
+    }
+
+    #[test]
+    fn cache_clear_forgets_fetched_recipe_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let mut app = app(temp.path());
+
+        let spec = "https://example.com/gist/baz.recipe.yaml"
+            .parse::<Spec>()
+            .unwrap();
+        let dir = app.packages_dir.join(spec.checkout_path());
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("baz.recipe.yaml"), "").unwrap();
+        app.state.mark_fetched(&spec.checkout());
+        app.state.mark_fetched("foo/bar");
+
+        let paths = app.cached_paths().unwrap();
+        assert_eq!(paths, [(spec.checkout(), spec.checkout_path())]);
+
+        app.cache_remove(true, false).unwrap();
+        assert!(!dir.exists());
+        assert_eq!(app.state.fetched().collect::<Vec<_>>(), ["foo/bar"]);
+
+        // Also forgotten when loaded again
+        let state = State::load(&temp.path().join("state.yaml")).unwrap();
+        assert!(state.fetched_since(&spec.checkout()).is_none());
+    }
+
+    fn signatures(packages: &[&str]) -> Signatures {
+        Signatures {
+            ssh: vec!["ssh-ed25519 AAAA".to_string()],
+            packages: packages.iter().map(|x| x.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn signature_required_for_repository() {
+        let spec = "foo/bar:baz".parse::<Spec>().unwrap();
+        assert!(requires_signature(&signatures(&[]), &spec).unwrap());
+        assert!(requires_signature(&signatures(&["foo/bar"]), &spec).unwrap());
+        assert!(!requires_signature(&signatures(&["foo/qux"]), &spec).unwrap());
+        assert!(!requires_signature(&Signatures::default(), &spec).unwrap());
+    }
+
+    #[test]
+    fn signature_required_refuses_recipe_files() {
+        let targets = [
+            "foo/bar:https://example.com/baz.recipe.yaml",
+            "foo/bar:/tmp/baz.recipe.yaml",
+            "https://example.com/baz.recipe.yaml",
+        ];
+
+        for target in targets {
+            let spec = target.parse::<Spec>().unwrap();
+            let error = requires_signature(&signatures(&[]), &spec).unwrap_err();
+            assert!(
+                str::contains(&error.to_string(), "baz.recipe.yaml"),
+                "{error}"
+            );
+        }
+
+        // A recipe file moved onto its `source` is still outside the repository
+        let spec = "https://example.com/baz.recipe.yaml"
+            .parse::<Spec>()
+            .unwrap();
+        let spec = spec.with_source("foo/bar".parse().unwrap()).unwrap();
+        assert!(requires_signature(&signatures(&["foo/bar"]), &spec).is_err());
+        assert!(!requires_signature(&signatures(&["foo/qux"]), &spec).unwrap());
     }
 }
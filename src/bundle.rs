@@ -0,0 +1,157 @@
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use pathdiff::diff_paths;
+use saphyr::{Hash, Yaml, YamlEmitter};
+use walkdir::WalkDir;
+
+use crate::fetcher::REVISION_FILE;
+use crate::Result;
+
+const LOCK_FILE: &str = "rimeka.lock";
+
+const PACKAGES_DIR: &str = "packages";
+
+/// Targets in a bundle, with the commits they were fetched at.
+#[derive(Debug, Default)]
+pub struct Lock {
+    pub packages: Vec<Locked>,
+}
+
+#[derive(Debug)]
+pub struct Locked {
+    pub target: String,
+    pub commit: String,
+    /// Whether the signature was verified when creating the bundle, as the history
+    /// needed to verify it is left out
+    pub verified: bool,
+}
+
+/// A checkout to be written into a bundle.
+#[derive(Debug)]
+pub struct Checkout {
+    /// Path relative to the packages directory
+    pub path: PathBuf,
+    pub dir: PathBuf,
+    pub commit: String,
+}
+
+impl Lock {
+    fn parse(yaml: &str) -> Result<Self> {
+        let docs = Yaml::load_from_str(yaml)?;
+        let Some(doc) = docs.first() else {
+            bail!("empty lock file");
+        };
+
+        let mut lock = Self::default();
+        for package in doc["packages"].as_vec().into_iter().flatten() {
+            let (Some(target), Some(commit)) =
+                (package["target"].as_str(), package["commit"].as_str())
+            else {
+                bail!("missing `target` or `commit` in lock file");
+            };
+
+            lock.packages.push(Locked {
+                target: target.to_string(),
+                commit: commit.to_string(),
+                verified: package["verified"].as_bool().unwrap_or(false),
+            });
+        }
+
+        Ok(lock)
+    }
+
+    fn to_yaml(&self) -> Result<String> {
+        let packages = self
+            .packages
+            .iter()
+            .map(|x| {
+                let mut hash = Hash::new();
+                hash.insert(Yaml::from_str("target"), Yaml::String(x.target.clone()));
+                hash.insert(Yaml::from_str("commit"), Yaml::String(x.commit.clone()));
+                hash.insert(Yaml::from_str("verified"), Yaml::Boolean(x.verified));
+                Yaml::Hash(hash)
+            })
+            .collect();
+
+        let mut doc = Hash::new();
+        doc.insert(Yaml::from_str("packages"), Yaml::Array(packages));
+
+        let mut out = String::new();
+        let mut emitter = YamlEmitter::new(&mut out);
+        emitter.dump(&Yaml::Hash(doc))?;
+
+        Ok(out + "\n")
+    }
+}
+
+/// Writes a gzipped tarball of the lock and the checkouts. The git directories are left
+/// out, and the commit of each checkout is written as the archive backend does, so
+/// the packages can be read without git.
+pub fn create(path: &Path, checkouts: &[Checkout], lock: &Lock) -> Result {
+    let file =
+        File::create(path).with_context(|| format!("failed to create {}", path.display()))?;
+    let mut builder = tar::Builder::new(GzEncoder::new(file, Compression::default()));
+
+    append_file(
+        &mut builder,
+        Path::new(LOCK_FILE),
+        lock.to_yaml()?.as_bytes(),
+    )?;
+
+    for checkout in checkouts {
+        let base = Path::new(PACKAGES_DIR).join(&checkout.path);
+
+        let walker = WalkDir::new(&checkout.dir)
+            .into_iter()
+            .filter_entry(|x| x.file_name() != ".git");
+
+        for entry in walker {
+            let entry = entry?;
+            let relative =
+                diff_paths(entry.path(), &checkout.dir).expect("walked path shouldn't be relative");
+            if !entry.file_type().is_file() || relative == Path::new(REVISION_FILE) {
+                continue;
+            }
+
+            builder.append_path_with_name(entry.path(), base.join(relative))?;
+        }
+
+        let revision = format!("{}\n", checkout.commit);
+        append_file(&mut builder, &base.join(REVISION_FILE), revision.as_bytes())?;
+    }
+
+    builder.into_inner()?.finish()?;
+    Ok(())
+}
+
+/// Extracts a bundle into the directory, returning its lock.
+pub fn extract(path: &Path, dest: &Path) -> Result<Lock> {
+    let file = File::open(path).with_context(|| format!("failed to open {}", path.display()))?;
+    tar::Archive::new(GzDecoder::new(file)).unpack(dest)?;
+
+    let lock = std::fs::read_to_string(dest.join(LOCK_FILE)).context("missing lock file")?;
+    Lock::parse(&lock).context("corrupted lock file")
+}
+
+/// Returns the directory of the packages in an extracted bundle.
+pub fn packages_dir(dir: &Path) -> PathBuf {
+    dir.join(PACKAGES_DIR)
+}
+
+fn append_file<W: std::io::Write>(
+    builder: &mut tar::Builder<W>,
+    path: &Path,
+    data: &[u8],
+) -> Result {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_cksum();
+
+    Ok(builder.append_data(&mut header, path, data)?)
+}
@@ -0,0 +1,547 @@
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{bail, Context};
+use base64::prelude::*;
+use glob::{MatchOptions, Pattern};
+use saphyr::Yaml;
+
+use crate::fetcher::{Backend, LocalChanges};
+use crate::Result;
+
+#[derive(Debug)]
+pub struct Config {
+    pub hooks: Hooks,
+    pub fetcher: FetcherConfig,
+    pub signatures: Signatures,
+    pub trusted: Trusted,
+    /// Number of previously installed commits kept for each package
+    pub history_limit: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            hooks: Hooks::default(),
+            fetcher: FetcherConfig::default(),
+            signatures: Signatures::default(),
+            trusted: Trusted::default(),
+            history_limit: 5,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+
+        let yaml = std::fs::read_to_string(path).context("failed to read config file")?;
+        let docs = Yaml::load_from_str(&yaml).context("failed to parse config file")?;
+
+        let Some(doc) = docs.first() else {
+            return Ok(Self::default());
+        };
+
+        let default = Self::default();
+
+        Ok(Self {
+            hooks: Hooks::parse(&doc["hooks"]).context("invalid `hooks` in config file")?,
+            fetcher: FetcherConfig::parse(&doc["fetcher"])
+                .context("invalid `fetcher` in config file")?,
+            signatures: Signatures::parse(&doc["signatures"])
+                .context("invalid `signatures` in config file")?,
+            trusted: Trusted::parse(&doc["trusted"]).context("invalid `trusted` in config file")?,
+            history_limit: match &doc["history_limit"] {
+                Yaml::BadValue | Yaml::Null => default.history_limit,
+                x => x
+                    .as_i64()
+                    .and_then(|x| usize::try_from(x).ok())
+                    .context("invalid `history_limit` in config file")?,
+            },
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Hooks {
+    pub pre_fetch: Vec<String>,
+    pub post_install: Vec<String>,
+    pub post_run: Vec<String>,
+}
+
+impl Hooks {
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        if yaml.is_badvalue() || yaml.is_null() {
+            return Ok(Self::default());
+        }
+
+        Ok(Self {
+            pre_fetch: strings(&yaml["pre_fetch"]).context("invalid `pre_fetch`")?,
+            post_install: strings(&yaml["post_install"]).context("invalid `post_install`")?,
+            post_run: strings(&yaml["post_run"]).context("invalid `post_run`")?,
+        })
+    }
+}
+
+/// Keys that the installed revisions must be signed with.
+#[derive(Debug, Default)]
+pub struct Signatures {
+    /// ASCII-armored GPG public keys
+    pub gpg: Vec<String>,
+    /// SSH public keys, e.g. `ssh-ed25519 AAAA...`
+    pub ssh: Vec<String>,
+    /// Repositories required to be signed, or all if empty
+    pub packages: Vec<String>,
+}
+
+impl Signatures {
+    /// Returns whether the revisions of the repository must be signed.
+    pub fn is_required(&self, repo: &str) -> bool {
+        let has_keys = !self.gpg.is_empty() || !self.ssh.is_empty();
+        has_keys && (self.packages.is_empty() || self.packages.iter().any(|x| x == repo))
+    }
+
+    /// Accepts SSH public keys, or paths to files of GPG or SSH public keys.
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        let mut signatures = Self {
+            packages: strings(&yaml["packages"]).context("invalid `packages`")?,
+            ..Default::default()
+        };
+
+        for key in strings(&yaml["keys"]).context("invalid `keys`")? {
+            if is_ssh_key(&key) {
+                signatures.ssh.push(key);
+                continue;
+            }
+
+            let path = shellexpand::tilde(&key);
+            let text = std::fs::read_to_string(path.as_ref())
+                .with_context(|| format!("failed to read key file {key}"))?;
+
+            if text.contains("-----BEGIN PGP PUBLIC KEY BLOCK-----") {
+                signatures.gpg.push(text);
+                continue;
+            }
+
+            let keys = text.lines().map(str::trim).filter(|x| is_ssh_key(x));
+            let count = signatures.ssh.len();
+            signatures.ssh.extend(keys.map(String::from));
+            if signatures.ssh.len() == count {
+                bail!("no public key found in {key}");
+            }
+        }
+
+        Ok(signatures)
+    }
+}
+
+/// Sources allowed to be installed without `--allow-untrusted`, as patterns of
+/// `host/owner/repo`. Any source is allowed if there is no pattern.
+#[derive(Debug, Default)]
+pub struct Trusted {
+    patterns: Vec<Pattern>,
+}
+
+impl Trusted {
+    pub fn is_enabled(&self) -> bool {
+        !self.patterns.is_empty()
+    }
+
+    pub fn allows(&self, host: &str, repo: &str) -> bool {
+        let source = format!("{host}/{repo}");
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..Default::default()
+        };
+
+        !self.is_enabled()
+            || self
+                .patterns
+                .iter()
+                .any(|x| x.matches_with(&source, options))
+    }
+
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        let patterns = strings(yaml)?
+            .iter()
+            .map(|x| Pattern::new(x).with_context(|| format!("invalid pattern `{x}`")))
+            .try_collect()?;
+
+        Ok(Self { patterns })
+    }
+}
+
+fn is_ssh_key(key: &str) -> bool {
+    ["ssh-", "ecdsa-", "sk-"].iter().any(|x| key.starts_with(x))
+}
+
+#[derive(Debug, Default)]
+pub struct FetcherConfig {
+    /// Backend used when no other rule applies
+    pub backend: Backend,
+    /// Backends by host, e.g. `github.com`
+    pub hosts: HashMap<String, Backend>,
+    /// Sources by repository, e.g. `iDvel/rime-ice`
+    pub packages: HashMap<String, Source>,
+    pub archive: ArchiveConfig,
+    pub mirrors: Mirrors,
+    pub network: Network,
+    /// Repositories fetched within this duration are not fetched again
+    pub ttl: Duration,
+    /// What to do with the local changes in cached checkouts
+    pub local_changes: LocalChanges,
+    /// Check out only the files that the recipes may install
+    pub sparse: bool,
+}
+
+/// URL templates of the archive backend, with `{host}`, `{repo}` and `{ref}` replaced
+/// by the host, the repository and the branch or commit.
+#[derive(Debug)]
+pub struct ArchiveConfig {
+    /// URL of a gzipped tarball or a zip archive
+    pub url: String,
+    /// URL responding with the commit of a branch in plain text
+    pub revision_url: Option<String>,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            url: "https://codeload.github.com/{repo}/tar.gz/{ref}".to_string(),
+            revision_url: Some("https://api.github.com/repos/{repo}/commits/{ref}".to_string()),
+        }
+    }
+}
+
+/// URL prefix rewrite rules, e.g. to fetch from a mirror where github.com is slow or
+/// blocked.
+#[derive(Debug, Default, Clone)]
+pub struct Mirrors {
+    /// Prefixes and their replacements, in the order they are tried
+    rules: Vec<(String, Vec<String>)>,
+}
+
+impl Mirrors {
+    #[cfg(test)]
+    pub fn new(rules: Vec<(String, Vec<String>)>) -> Self {
+        Self { rules }
+    }
+
+    /// Returns the URLs to try in order, with the prefix of the first matching rule
+    /// replaced by each of its replacements.
+    pub fn urls(&self, url: &str) -> Vec<String> {
+        for (prefix, replacements) in &self.rules {
+            if let Some(rest) = url.strip_prefix(prefix.as_str()) {
+                return replacements.iter().map(|x| format!("{x}{rest}")).collect();
+            }
+        }
+
+        vec![url.to_string()]
+    }
+}
+
+/// Retries, time limit and credentials of network operations.
+#[derive(Debug, Clone)]
+pub struct Network {
+    /// Number of retries after a transient failure, e.g. a dropped connection
+    pub retries: u32,
+    /// Time limit of each clone, fetch or download, including its retries and
+    /// fallbacks to other URLs
+    pub timeout: Duration,
+    pub credentials: Credentials,
+    /// Refuse any network access, set by `--offline`
+    pub offline: bool,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            timeout: Duration::from_secs(600),
+            credentials: Credentials::default(),
+            offline: false,
+        }
+    }
+}
+
+/// Access tokens of private repositories by host. They are only sent to HTTPS URLs of
+/// the same host, and never shown.
+#[derive(Default, Clone)]
+pub struct Credentials {
+    tokens: HashMap<String, String>,
+}
+
+impl Credentials {
+    /// Returns the token of the host of an HTTPS URL.
+    pub fn token(&self, url: &str) -> Option<&str> {
+        let authority = url.strip_prefix("https://")?.split('/').next()?;
+        let host = authority.rsplit('@').next()?.split(':').next()?;
+        self.tokens.get(host).map(String::as_str)
+    }
+
+    /// Returns the `Authorization` header value for git over HTTPS with the token of
+    /// the host.
+    pub fn git_authorization(&self, url: &str) -> Option<String> {
+        let token = self.token(url)?;
+        let credentials = BASE64_STANDARD.encode(format!("x-access-token:{token}"));
+        Some(format!("Basic {credentials}"))
+    }
+}
+
+impl Debug for Credentials {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credentials")
+            .field("hosts", &self.tokens.keys())
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+pub struct Source {
+    pub backend: Backend,
+    pub path: Option<PathBuf>,
+}
+
+impl FetcherConfig {
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        if yaml.is_badvalue() || yaml.is_null() {
+            return Ok(Self::default());
+        }
+
+        let mut config = Self::default();
+
+        if let Some(backend) = yaml["backend"].as_str() {
+            config.backend = backend.parse()?;
+        }
+
+        for (host, backend) in yaml["hosts"].as_hash().into_iter().flatten() {
+            let host = host.as_str().context("host must be a string")?;
+            let backend = backend.as_str().context("backend must be a string")?;
+            config.hosts.insert(host.to_string(), backend.parse()?);
+        }
+
+        for (repo, source) in yaml["packages"].as_hash().into_iter().flatten() {
+            let repo = repo.as_str().context("repository must be a string")?;
+            let source = Source::parse(source).with_context(|| format!("invalid `{repo}`"))?;
+            config.packages.insert(repo.to_string(), source);
+        }
+
+        if let Some(local_changes) = yaml["local_changes"].as_str() {
+            config.local_changes = local_changes.parse()?;
+        }
+
+        match &yaml["sparse"] {
+            Yaml::BadValue | Yaml::Null => {}
+            x => config.sparse = x.as_bool().context("`sparse` must be a boolean")?,
+        }
+
+        if let Some(ttl) = duration(&yaml["ttl"]).context("invalid `ttl`")? {
+            config.ttl = ttl;
+        }
+
+        match &yaml["retries"] {
+            Yaml::BadValue | Yaml::Null => {}
+            x => {
+                config.network.retries = x
+                    .as_i64()
+                    .and_then(|x| u32::try_from(x).ok())
+                    .context("invalid `retries`")?;
+            }
+        }
+
+        if let Some(timeout) = duration(&yaml["timeout"]).context("invalid `timeout`")? {
+            config.network.timeout = timeout;
+        }
+
+        for (host, token) in yaml["tokens"].as_hash().into_iter().flatten() {
+            let host = host.as_str().context("host must be a string")?;
+            let token = token
+                .as_str()
+                .with_context(|| format!("token of `{host}` must be a string"))?;
+
+            // Hosts whose environment variable is not set are left without a token
+            if let Ok(token) = shellexpand::env(token) {
+                if !token.is_empty() {
+                    let tokens = &mut config.network.credentials.tokens;
+                    tokens.insert(host.to_string(), token.into_owned());
+                }
+            }
+        }
+
+        let archive = &yaml["archive"];
+        if let Some(url) = archive["url"].as_str() {
+            config.archive.url = url.to_string();
+        }
+        match &archive["revision_url"] {
+            Yaml::BadValue => {}
+            Yaml::Null => config.archive.revision_url = None,
+            x => {
+                let url = x.as_str().context("`revision_url` must be a string")?;
+                config.archive.revision_url = Some(url.to_string());
+            }
+        }
+
+        for (prefix, replacements) in yaml["mirrors"].as_hash().into_iter().flatten() {
+            let prefix = prefix.as_str().context("prefix must be a string")?;
+            let replacements =
+                strings(replacements).with_context(|| format!("invalid mirrors of `{prefix}`"))?;
+            if replacements.is_empty() {
+                bail!("no mirror of `{prefix}`");
+            }
+
+            config
+                .mirrors
+                .rules
+                .push((prefix.to_string(), replacements));
+        }
+
+        Ok(config)
+    }
+}
+
+impl Source {
+    /// Accepts either a backend name or a mapping with `backend` and `path`.
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        if let Some(backend) = yaml.as_str() {
+            return Ok(Self {
+                backend: backend.parse()?,
+                path: None,
+            });
+        }
+
+        let backend = yaml["backend"].as_str().context("`backend` is required")?;
+        let path = yaml["path"]
+            .as_str()
+            .map(|x| PathBuf::from(shellexpand::tilde(x).as_ref()));
+
+        Ok(Self {
+            backend: backend.parse()?,
+            path,
+        })
+    }
+}
+
+/// Accepts either seconds or a number with one of the units `s`, `m`, `h` and `d`.
+fn duration(yaml: &Yaml) -> Result<Option<Duration>> {
+    let text = match yaml {
+        Yaml::BadValue | Yaml::Null => return Ok(None),
+        Yaml::Integer(x) => return Ok(Some(Duration::from_secs(u64::try_from(*x)?))),
+        Yaml::String(x) => x.trim(),
+        _ => bail!("expected a duration"),
+    };
+
+    let (number, unit) =
+        text.split_at(text.len() - text.trim_start_matches(char::is_numeric).len());
+    let seconds = match unit.trim() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown unit of duration: {unit}"),
+    };
+
+    let number = number.parse::<u64>().context("expected a duration")?;
+    let seconds = number
+        .checked_mul(seconds)
+        .context("duration is too long")?;
+    Ok(Some(Duration::from_secs(seconds)))
+}
+
+/// Accepts either a single string or a list of strings.
+fn strings(yaml: &Yaml) -> Result<Vec<String>> {
+    match yaml {
+        Yaml::BadValue | Yaml::Null => Ok(Vec::new()),
+        Yaml::String(x) => Ok(vec![x.clone()]),
+        Yaml::Array(xs) => xs
+            .iter()
+            .map(|x| x.as_str().map(String::from))
+            .collect::<Option<_>>()
+            .context("expected a string"),
+        _ => bail!("expected a string or a list of strings"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn duration_in_seconds() {
+        let secs = |x| Some(Duration::from_secs(x));
+        assert_eq!(duration(&Yaml::Integer(30)).unwrap(), secs(30));
+        assert_eq!(duration(&Yaml::from_str("90")).unwrap(), secs(90));
+        assert_eq!(duration(&Yaml::from_str("90s")).unwrap(), secs(90));
+    }
+
+    #[test]
+    fn duration_with_unit() {
+        let secs = |x| Some(Duration::from_secs(x));
+        assert_eq!(duration(&Yaml::from_str("5m")).unwrap(), secs(300));
+        assert_eq!(duration(&Yaml::from_str(" 2 h ")).unwrap(), secs(7200));
+        assert_eq!(duration(&Yaml::from_str("1d")).unwrap(), secs(86400));
+    }
+
+    #[test]
+    fn duration_missing() {
+        assert_eq!(duration(&Yaml::Null).unwrap(), None);
+        assert_eq!(duration(&Yaml::BadValue).unwrap(), None);
+    }
+
+    #[test]
+    fn duration_invalid() {
+        for text in ["1w", "m", "", "1.5h", "-1"] {
+            assert!(duration(&Yaml::String(text.to_string())).is_err(), "{text}");
+        }
+        assert!(duration(&Yaml::from_str("99999999999999999d")).is_err());
+        assert!(duration(&Yaml::Integer(-1)).is_err());
+        assert!(duration(&Yaml::Boolean(true)).is_err());
+    }
+
+    fn mirrors() -> Mirrors {
+        Mirrors {
+            rules: vec![
+                (
+                    "https://github.com/".to_string(),
+                    vec![
+                        "https://mirror.example.com/".to_string(),
+                        "https://github.com/".to_string(),
+                    ],
+                ),
+                (
+                    "https://".to_string(),
+                    vec!["https://proxy.example.com/".to_string()],
+                ),
+            ],
+        }
+    }
+
+    #[test]
+    fn mirrors_in_order() {
+        assert_eq!(
+            mirrors().urls("https://github.com/foo/bar"),
+            [
+                "https://mirror.example.com/foo/bar",
+                "https://github.com/foo/bar"
+            ]
+        );
+    }
+
+    #[test]
+    fn mirrors_of_first_matching_rule() {
+        assert_eq!(
+            mirrors().urls("https://example.com/baz.recipe.yaml"),
+            ["https://proxy.example.com/example.com/baz.recipe.yaml"]
+        );
+    }
+
+    #[test]
+    fn mirrors_without_matching_rule() {
+        let url = "git@github.com:foo/bar";
+        assert_eq!(mirrors().urls(url), [url]);
+        assert_eq!(Mirrors::default().urls(url), [url]);
+    }
+}
@@ -0,0 +1,361 @@
+use std::fs::File;
+use std::io::{Read, Seek};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use flate2::read::GzDecoder;
+use owo_colors::OwoColorize;
+use tar::EntryType;
+use zip::ZipArchive;
+
+use super::{fallback, request, short, warn_unfetched, Backend, Changes, Fetcher};
+use crate::config::{FetcherConfig, Mirrors, Network};
+use crate::progress::Log;
+use crate::spec::Spec;
+use crate::Result;
+
+/// Name of the file recording the revision of an extracted archive.
+pub const REVISION_FILE: &str = ".rimeka-revision";
+
+/// Fetches packages by downloading and extracting archives over HTTP, for machines
+/// without the `git` command line.
+#[derive(Debug)]
+pub struct ArchiveFetcher {
+    host: String,
+    repo: String,
+    branch: Option<String>,
+    dir: PathBuf,
+    url: String,
+    revision_url: Option<String>,
+    mirrors: Mirrors,
+    network: Network,
+}
+
+impl Fetcher for ArchiveFetcher {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn url(&self) -> Option<String> {
+        let url = self.expand(&self.url, self.reference());
+        self.mirrors.urls(&url).into_iter().next()
+    }
+
+    fn fetch(&self, log: &Log) -> Result {
+        // Resolve the revision first to skip downloading an unchanged archive, which
+        // is still downloaded if the revision cannot be resolved, e.g. when rate limited
+        let head = self.head();
+        let revision = match head {
+            Ok(_) if self.revision_url.is_some() => self.resolve(log, self.reference()).ok(),
+            _ => None,
+        };
+
+        match (revision, head) {
+            (Some(revision), Ok(head)) if revision == head => {
+                log.println(format!("Already up to date at {}", short(&head)));
+                log.println("");
+                Ok(())
+            }
+            (Some(revision), _) => self.download(log, &revision),
+            (None, _) => self.download(log, self.reference()),
+        }
+    }
+
+    fn head(&self) -> Result<String> {
+        let revision = std::fs::read_to_string(self.dir.join(REVISION_FILE))
+            .context("the package has not been fetched")?;
+
+        Ok(revision.trim().to_string())
+    }
+
+    fn remote_head(&self) -> Result<String> {
+        self.resolve(&Log::Stdout, self.reference())
+    }
+
+    fn checkout(&self, revision: &str) -> Result {
+        self.download(&Log::Stdout, revision)
+    }
+
+    fn changes(&self, _: &str, _: &str) -> Result<Option<Changes>> {
+        Ok(None)
+    }
+}
+
+impl ArchiveFetcher {
+    pub fn new(spec: &Spec, dir: &Path, config: &FetcherConfig) -> Self {
+        Self {
+            host: spec.host().to_string(),
+            repo: spec.repo().to_string(),
+            branch: spec.branch().map(|x| x.to_string()),
+            dir: dir.to_path_buf(),
+            url: config.archive.url.clone(),
+            revision_url: config.archive.revision_url.clone(),
+            mirrors: config.mirrors.clone(),
+            network: config.network.clone(),
+        }
+    }
+
+    fn reference(&self) -> &str {
+        self.branch.as_deref().unwrap_or("HEAD")
+    }
+
+    fn expand(&self, template: &str, reference: &str) -> String {
+        template
+            .replace("{host}", &self.host)
+            .replace("{repo}", &self.repo)
+            .replace("{ref}", reference)
+    }
+
+    /// Resolves a branch or commit to a commit with the `revision_url`.
+    fn resolve(&self, log: &Log, reference: &str) -> Result<String> {
+        if is_commit(reference) {
+            return Ok(reference.to_string());
+        }
+
+        let template = self
+            .revision_url
+            .as_ref()
+            .context("`revision_url` is required to resolve the revision")?;
+
+        let url = self.expand(template, reference);
+        fallback(log, &self.mirrors.urls(&url), &self.network, |url| {
+            let body = request(&self.network, url)
+                .set("Accept", "application/vnd.github.sha")
+                .call()
+                .with_context(|| format!("failed to request {url}"))?
+                .into_string()?;
+
+            let revision = body.trim();
+            if !is_commit(revision) {
+                bail!("unexpected response from {url}");
+            }
+
+            Ok(revision.to_string())
+        })
+    }
+
+    fn download(&self, log: &Log, reference: &str) -> Result {
+        let url = self.expand(&self.url, reference);
+        let urls = self.mirrors.urls(&url);
+        log.println(format!("- {} {}", "Downloading:".cyan(), urls[0]));
+
+        let download = self.sibling(".download");
+        let staging = self.sibling(".staging");
+
+        super::download(log, &urls, &self.network, &download)?;
+
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::create_dir_all(&staging)?;
+
+        let revision = extract(File::open(&download)?, &staging)
+            .with_context(|| format!("failed to extract {url}"))?;
+        let revision = match revision {
+            Some(revision) => revision,
+            None => self.resolve(log, reference)?,
+        };
+
+        // Archives of git repositories wrap the files in a single top-level directory
+        let mut entries = std::fs::read_dir(&staging)?.collect::<Result<Vec<_>, _>>()?;
+        let root = match entries.as_slice() {
+            [entry] if entry.file_type()?.is_dir() => entries.remove(0).path(),
+            _ => staging.clone(),
+        };
+
+        std::fs::write(root.join(REVISION_FILE), format!("{revision}\n"))?;
+
+        if self.dir.exists() {
+            std::fs::remove_dir_all(&self.dir)?;
+        }
+        std::fs::rename(&root, &self.dir)?;
+
+        if staging.exists() {
+            std::fs::remove_dir_all(&staging)?;
+        }
+        std::fs::remove_file(&download)?;
+
+        log.println(format!("- {} {}", "Revision:".cyan(), revision));
+        warn_unfetched(log, &self.dir, Backend::Archive);
+        log.println("");
+        Ok(())
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut path = self.dir.clone().into_os_string();
+        path.push(suffix);
+        PathBuf::from(path)
+    }
+}
+
+/// Extracts a zip or gzipped tar archive, returning the commit recorded by `git archive`.
+fn extract(mut file: File, dest: &Path) -> Result<Option<String>> {
+    let mut magic = [0; 2];
+    file.read_exact(&mut magic)?;
+    file.rewind()?;
+
+    match &magic {
+        b"PK" => {
+            let mut archive = ZipArchive::new(file)?;
+            let comment = String::from_utf8_lossy(archive.comment())
+                .trim()
+                .to_string();
+            archive.extract(dest)?;
+            Ok(Some(comment).filter(|x| is_commit(x)))
+        }
+        [0x1f, 0x8b] => {
+            let mut archive = tar::Archive::new(GzDecoder::new(file));
+            let mut revision = None;
+
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+
+                if entry.header().entry_type() == EntryType::XGlobalHeader {
+                    for extension in entry.pax_extensions()?.into_iter().flatten() {
+                        let extension = extension?;
+                        if extension.key()? == "comment" && is_commit(extension.value()?) {
+                            revision = Some(extension.value()?.to_string());
+                        }
+                    }
+                    continue;
+                }
+
+                entry.unpack_in(dest)?;
+            }
+
+            Ok(revision)
+        }
+        _ => bail!("unsupported archive format"),
+    }
+}
+
+fn is_commit(reference: &str) -> bool {
+    reference.len() == 40 && reference.chars().all(|x| x.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use super::super::testing::{self, git};
+    use super::*;
+
+    /// Serves archives of the repository made by `git archive`, and its commit at the
+    /// revision URL, returning the address and the requested paths.
+    fn serve(repo: &Path) -> (String, Arc<Mutex<Vec<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let repo = repo.to_path_buf();
+        let log = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let path = line.split(' ').nth(1).unwrap_or_default().to_string();
+                // Skip the headers up to the empty line
+                while reader.read_line(&mut line).unwrap() > 2 {}
+
+                let body = match path.rsplit_once('/').unwrap_or_default() {
+                    (_, "HEAD.tar.gz") => archive(&repo, "tar.gz"),
+                    (_, "HEAD.zip") => archive(&repo, "zip"),
+                    (x, "HEAD") if x.ends_with("/commits") => {
+                        git(&repo, &["rev-parse", "HEAD"]).into_bytes()
+                    }
+                    _ => Vec::new(),
+                };
+                log.lock().unwrap().push(path);
+
+                let status = match body.is_empty() {
+                    true => "404 Not Found",
+                    false => "200 OK",
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+
+        (address, requests)
+    }
+
+    fn archive(repo: &Path, format: &str) -> Vec<u8> {
+        let output = std::process::Command::new("git")
+            .current_dir(repo)
+            .args([
+                "archive",
+                &format!("--format={format}"),
+                "--prefix=bar-main/",
+                "HEAD",
+            ])
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    fn fetch_twice(format: &str) {
+        let temp = tempfile::tempdir().unwrap();
+        let repo = temp.path().join("repo");
+        let commit = testing::repo(
+            &repo,
+            &[("demo.schema.yaml", "schema"), ("opencc/a.json", "{}")],
+        );
+
+        let (address, requests) = serve(&repo);
+        let mut config = FetcherConfig::default();
+        config.archive.url = format!("http://{address}/{{repo}}/{{ref}}.{format}");
+        config.archive.revision_url = Some(format!("http://{address}/{{repo}}/commits/{{ref}}"));
+
+        let spec = "foo/bar".parse::<Spec>().unwrap();
+        let dir = temp.path().join("packages").join("foo").join("bar");
+        let fetcher = ArchiveFetcher::new(&spec, &dir, &config);
+
+        let (log, bar) = testing::log();
+        fetcher.fetch(&log).unwrap();
+
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("demo.schema.yaml"), "schema");
+        assert_eq!(read("opencc/a.json"), "{}");
+        assert_eq!(read(REVISION_FILE), format!("{commit}\n"));
+        assert_eq!(fetcher.head().unwrap(), commit);
+        assert!(!fetcher.sibling(".download").exists());
+        assert!(!fetcher.sibling(".staging").exists());
+
+        fetcher.fetch(&log).unwrap();
+        assert_eq!(
+            bar.message(),
+            format!("Already up to date at {}", short(&commit))
+        );
+
+        let archive = format!("/foo/bar/HEAD.{format}");
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests.iter().filter(|x| **x == archive).count(), 1);
+    }
+
+    #[test]
+    fn fetch_tarball() {
+        fetch_twice("tar.gz");
+    }
+
+    #[test]
+    fn fetch_zip() {
+        fetch_twice("zip");
+    }
+
+    #[test]
+    fn commit_reference() {
+        assert!(is_commit("0123456789abcdef0123456789abcdef01234567"));
+        assert!(!is_commit("main"));
+        assert!(!is_commit("0123456"));
+    }
+}
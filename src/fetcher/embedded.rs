@@ -0,0 +1,578 @@
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{bail, Context};
+use gix::bstr::BString;
+use gix::progress::Discard;
+use gix::protocol::handshake::Ref;
+use gix::protocol::transport::client::{http, Transport};
+use gix::refs::transaction::{Change, LogChange, PreviousValue, RefEdit};
+use gix::refs::Target;
+use gix::remote::fetch::Shallow;
+use gix::remote::{Connection, Direction};
+use gix::traverse::tree::Recorder;
+use gix::worktree::stack::state::attributes::Source;
+use gix::{ObjectId, Repository};
+
+use super::{
+    check_checkout_dir, describe_changes, discard_broken, discard_failed_clone, empty_dir, exclude,
+    fallback, log_local_changes, log_up_to_date, time_limit, tracking_refspec, warn_unfetched,
+    Backend, Changes, Fetcher, LocalChanges,
+};
+use crate::config::{FetcherConfig, Network};
+use crate::progress::Log;
+use crate::spec::Spec;
+use crate::Result;
+
+static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+/// Fetches packages with the built-in git implementation, for machines without the
+/// `git` command line.
+#[derive(Debug)]
+pub struct EmbeddedGitFetcher {
+    /// Candidate URLs of the repository, tried in order
+    urls: Vec<String>,
+    dir: PathBuf,
+    branch: Option<String>,
+    local_changes: LocalChanges,
+    network: Network,
+}
+
+impl Fetcher for EmbeddedGitFetcher {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn url(&self) -> Option<String> {
+        self.urls.first().cloned()
+    }
+
+    fn fetch(&self, log: &Log) -> Result {
+        discard_broken(log, &self.dir, self.check())?;
+
+        if self.dir.join(".git").is_dir() {
+            self.pull(log)
+        } else {
+            self.clone(log)
+        }
+    }
+
+    fn head(&self) -> Result<String> {
+        Ok(self.open()?.head_id()?.to_string())
+    }
+
+    fn remote_head(&self) -> Result<String> {
+        let repo = self.open()?;
+        Ok(self.remote_tip(&Log::Stdout, &repo)?.1.to_string())
+    }
+
+    /// Fetches the commit by its ID if the shallow clone lacks it.
+    fn checkout(&self, commit: &str) -> Result {
+        let repo = self.open()?;
+        let id = ObjectId::from_hex(commit.as_bytes()).context("invalid commit")?;
+
+        if self.local_changes != LocalChanges::Discard && is_modified(&repo)? {
+            bail!(
+                "{} has uncommitted changes; use --local-changes=discard to roll back over them",
+                self.dir.display()
+            );
+        }
+
+        if repo.find_object(id).is_err() {
+            let depth = Shallow::DepthAtRemote(NonZeroU32::MIN);
+            self.receive(&Log::Stdout, &repo, commit, depth)?;
+        }
+
+        let branch = local_branch(&repo)?;
+        self.reset(&repo, &branch, id)?;
+
+        println!("HEAD is now at {}", id.to_hex_with_len(7));
+        println!();
+        Ok(())
+    }
+
+    /// Lists the commits in between and the files differing between their trees.
+    fn changes(&self, from: &str, to: &str) -> Result<Option<Changes>> {
+        let repo = self.open()?;
+        let branch = local_branch(&repo)?;
+        let from = ObjectId::from_hex(from.as_bytes()).context("invalid commit")?;
+        let to = ObjectId::from_hex(to.as_bytes()).context("invalid commit")?;
+
+        let refspec = tracking_refspec(&branch);
+        for depth in [50, 500, 5000] {
+            if is_ancestor(&repo, from, to) {
+                break;
+            }
+
+            if !repo.is_shallow() {
+                return Ok(None);
+            }
+
+            self.receive(&Log::Stdout, &repo, &refspec, Shallow::Deepen(depth))?;
+        }
+
+        // Reopen the repository to pick up the deepened history
+        let repo = self.open()?;
+        if !is_ancestor(&repo, from, to) {
+            return Ok(None);
+        }
+
+        let mut log = Vec::new();
+        for info in repo.rev_walk([to]).with_hidden([from]).all()? {
+            let commit = info?.object()?;
+            let summary = commit.message()?.summary().to_string();
+            log.push(format!("{} {}", commit.id.to_hex_with_len(7), summary));
+        }
+
+        let old = tree_entries(&repo, from)?;
+        let new = tree_entries(&repo, to)?;
+        let mut files = new
+            .iter()
+            .filter(|(path, id)| old.get(*path) != Some(id))
+            .map(|(path, _)| path.clone())
+            .chain(old.keys().filter(|x| !new.contains_key(*x)).cloned())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        Ok(Some(Changes { log, files }))
+    }
+
+    fn ignore(&self, path: &str) -> Result {
+        exclude(&self.dir, path)
+    }
+}
+
+impl EmbeddedGitFetcher {
+    pub fn new(spec: &Spec, dir: &Path, config: &FetcherConfig) -> Self {
+        Self {
+            urls: config.mirrors.urls(&spec.url()),
+            dir: dir.to_path_buf(),
+            branch: spec.branch().map(|x| x.to_string()),
+            local_changes: config.local_changes,
+            network: config.network.clone(),
+        }
+    }
+
+    /// Opens the checkout, with a committer for the reflogs if none is configured, as
+    /// `git` falls back to one from the system.
+    fn open(&self) -> Result<Repository> {
+        let mut repo = gix::open(&self.dir)
+            .with_context(|| format!("failed to open {}", self.dir.display()))?;
+        repo.committer_or_set_generic_fallback()?;
+        Ok(repo)
+    }
+
+    /// Returns why gix cannot open the checkout or resolve its `HEAD`.
+    fn check(&self) -> Option<String> {
+        if let Some(problem) = check_checkout_dir(&self.dir) {
+            return Some(problem);
+        }
+
+        if !self.dir.join(".git").is_dir() {
+            return None;
+        }
+
+        let Ok(repo) = gix::open(&self.dir) else {
+            return Some("the repository is corrupted".to_string());
+        };
+
+        // The remote `origin` is not checked, as it is never used for fetching
+        if repo.head_id().is_err() {
+            return Some("the repository is corrupted".to_string());
+        }
+
+        None
+    }
+
+    fn clone(&self, log: &Log) -> Result {
+        log.println(format!("Cloning into '{}'...", self.dir.display()));
+        let result = fallback(log, &self.urls, &self.network, |url| {
+            empty_dir(&self.dir)?;
+
+            let mut prepare = gix::prepare_clone(url, &self.dir)?
+                .with_shallow(Shallow::DepthAtRemote(NonZeroU32::MIN));
+            if let Some(branch) = &self.branch {
+                prepare = prepare.with_ref_name(Some(branch.as_str()))?;
+            }
+
+            let network = self.network.clone();
+            prepare = prepare.configure_connection(move |connection| {
+                configure_transport(connection, &network).map_err(Into::into)
+            });
+
+            self.within(|interrupt| {
+                let (mut checkout, _) = prepare
+                    .fetch_then_checkout(Discard, interrupt)
+                    .with_context(|| format!("failed to clone {url}"))?;
+                checkout.main_worktree(Discard, interrupt)?;
+                Ok(())
+            })
+        });
+        discard_failed_clone(&self.dir, result)?;
+
+        warn_unfetched(log, &self.dir, Backend::EmbeddedGit);
+        log.println("");
+        Ok(())
+    }
+
+    fn pull(&self, log: &Log) -> Result {
+        let repo = self.open()?;
+        let (branch, tip) = self.remote_tip(log, &repo)?;
+
+        if !is_modified(&repo)? && repo.head_id()? == tip && local_branch(&repo)? == branch {
+            log_up_to_date(log, &branch, &tip.to_string());
+            log.println("");
+            return Ok(());
+        }
+
+        self.check_local_changes(log, &repo)?;
+
+        let refspec = tracking_refspec(&branch);
+        let depth = Shallow::DepthAtRemote(NonZeroU32::MIN);
+        self.receive(log, &repo, &refspec, depth)?;
+
+        let repo = self.open()?;
+        let id = repo
+            .find_reference(&format!("refs/remotes/origin/{branch}"))?
+            .peel_to_id()?
+            .detach();
+        self.reset(&repo, &branch, id)?;
+
+        log.println(format!("Switched to {branch} at {}", id.to_hex_with_len(7)));
+        warn_unfetched(log, &self.dir, Backend::EmbeddedGit);
+        log.println("");
+        Ok(())
+    }
+
+    /// Only refusing and discarding local changes are supported, as there is no stash
+    /// or rebase in the built-in implementation.
+    fn check_local_changes(&self, log: &Log, repo: &Repository) -> Result {
+        let modified = is_modified(repo)?;
+
+        let head = repo.head_id()?.detach();
+        let upstream = format!("refs/remotes/origin/{}", local_branch(repo)?);
+        let commits = match repo.find_reference(upstream.as_str()) {
+            Ok(mut upstream) => {
+                let upstream = upstream.peel_to_id()?.detach();
+                let merged = repo
+                    .rev_walk([upstream])
+                    .all()?
+                    .map(|x| Ok(x?.id))
+                    .collect::<Result<HashSet<_>>>()?;
+
+                let mut commits = 0;
+                for info in repo.rev_walk([head]).all()? {
+                    commits += usize::from(!merged.contains(&info?.id));
+                }
+                commits
+            }
+            Err(_) => 0,
+        };
+
+        let changes = describe_changes(modified, commits);
+
+        match self.local_changes {
+            _ if changes.is_empty() => {}
+            LocalChanges::Refuse => bail!(
+                "{} has {changes}; use --local-changes=discard to discard them",
+                self.dir.display()
+            ),
+            LocalChanges::Discard => {
+                log_local_changes(log, &format!("{changes} discarded"));
+            }
+            mode => bail!("--local-changes={mode} is not supported by the embedded-git backend"),
+        }
+
+        Ok(())
+    }
+
+    /// Finds the branch and its tip among the refs advertised for the branch, or for the
+    /// symbolic `HEAD` without one.
+    fn remote_tip(&self, log: &Log, repo: &Repository) -> Result<(String, ObjectId)> {
+        let refname = match &self.branch {
+            Some(branch) => format!("refs/heads/{branch}"),
+            None => "HEAD".to_string(),
+        };
+
+        let refs = self.remote_refs(log, repo, &refname)?;
+        let tip = refs.iter().find_map(|x| match x {
+            Ref::Direct {
+                full_ref_name,
+                object,
+            } if *full_ref_name == refname => Some((self.branch.clone()?, *object)),
+            Ref::Symbolic {
+                full_ref_name,
+                target,
+                object,
+                ..
+            } if *full_ref_name == refname => {
+                let branch = target.to_string().strip_prefix("refs/heads/")?.to_string();
+                Some((branch, *object))
+            }
+            _ => None,
+        });
+
+        match (tip, &self.branch) {
+            (Some(tip), _) => Ok(tip),
+            (None, Some(branch)) => bail!("branch `{branch}` not found in the remote"),
+            (None, None) => bail!("the remote does not advertise its default branch"),
+        }
+    }
+
+    /// Lists the remote refs matching the refspec, like `git ls-remote`.
+    fn remote_refs(&self, log: &Log, repo: &Repository, refspec: &str) -> Result<Vec<Ref>> {
+        fallback(log, &self.urls, &self.network, |url| {
+            let remote = repo
+                .remote_at(url)?
+                .with_refspecs([refspec], Direction::Fetch)?;
+            let mut connection = remote
+                .connect(Direction::Fetch)
+                .with_context(|| format!("failed to connect to {url}"))?;
+            configure_transport(&mut connection, &self.network)?;
+            let (map, _) = connection
+                .ref_map(Discard, Default::default())
+                .with_context(|| format!("failed to list refs of {url}"))?;
+
+            Ok(map.remote_refs)
+        })
+    }
+
+    fn receive(&self, log: &Log, repo: &Repository, refspec: &str, shallow: Shallow) -> Result {
+        fallback(log, &self.urls, &self.network, |url| {
+            self.within(|interrupt| {
+                let remote = repo
+                    .remote_at(url)?
+                    .with_refspecs([refspec], Direction::Fetch)?;
+                let mut connection = remote
+                    .connect(Direction::Fetch)
+                    .with_context(|| format!("failed to connect to {url}"))?;
+                configure_transport(&mut connection, &self.network)?;
+                connection
+                    .prepare_fetch(Discard, Default::default())?
+                    .with_shallow(shallow.clone())
+                    .receive(Discard, interrupt)
+                    .with_context(|| format!("failed to fetch {url}"))?;
+
+                Ok(())
+            })
+        })
+    }
+
+    /// Runs a network operation, interrupting it after the time limit.
+    fn within<T>(&self, operation: impl FnOnce(&AtomicBool) -> Result<T>) -> Result<T> {
+        let interrupt = AtomicBool::new(false);
+        let (done, timer) = mpsc::channel::<()>();
+        let timeout = time_limit(&self.network);
+
+        std::thread::scope(|s| {
+            let interrupt = &interrupt;
+            s.spawn(move || {
+                if let Err(RecvTimeoutError::Timeout) = timer.recv_timeout(timeout) {
+                    interrupt.store(true, Ordering::Relaxed);
+                }
+            });
+
+            let result = operation(interrupt);
+            drop(done);
+
+            match interrupt.load(Ordering::Relaxed) {
+                true => bail!("timed out after {}s", timeout.as_secs()),
+                false => result,
+            }
+        })
+    }
+
+    /// Points the branch and `HEAD` to the commit and rewrites the worktree, like
+    /// `git clean -xdf && git reset --hard`.
+    fn reset(&self, repo: &Repository, branch: &str, id: ObjectId) -> Result {
+        let refname = format!("refs/heads/{branch}");
+        repo.reference(refname.as_str(), id, PreviousValue::Any, "rimeka: reset")?;
+        repo.edit_reference(RefEdit {
+            change: Change::Update {
+                log: LogChange::default(),
+                expected: PreviousValue::Any,
+                new: Target::Symbolic(refname.as_str().try_into()?),
+            },
+            name: "HEAD".try_into()?,
+            deref: false,
+        })?;
+
+        let workdir = repo.workdir().context("the package is a bare repository")?;
+        for entry in std::fs::read_dir(workdir)? {
+            let entry = entry?;
+            if entry.file_name() == ".git" {
+                continue;
+            }
+
+            if entry.file_type()?.is_dir() {
+                std::fs::remove_dir_all(entry.path())?;
+            } else {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+
+        let tree = repo.find_commit(id)?.tree_id()?;
+        let mut index = repo.index_from_tree(&tree)?;
+        let mut options = repo.checkout_options(Source::IdMapping)?;
+        options.destination_is_initially_empty = true;
+
+        gix::worktree::state::checkout(
+            &mut index,
+            workdir,
+            repo.objects.clone().into_arc()?,
+            &Discard,
+            &Discard,
+            &INTERRUPT,
+            options,
+        )?;
+        index.write(Default::default())?;
+
+        Ok(())
+    }
+}
+
+/// Makes HTTP requests give up after the time limit, as the interrupt is not checked
+/// while waiting for the server, and sends the token of the host if any. Both are set
+/// on each request, as the `reqwest` backend ignores the other options.
+fn configure_transport<T: Transport>(
+    connection: &mut Connection<'_, '_, T>,
+    network: &Network,
+) -> Result {
+    let remote = connection.remote();
+    let url = remote
+        .url(Direction::Fetch)
+        .context("the remote has no URL")?
+        .to_bstring();
+
+    let Some(mut options) = remote.repo().transport_options(url.as_slice(), None)? else {
+        return Ok(());
+    };
+
+    if let Some(options) = options.downcast_mut::<http::Options>() {
+        let timeout = time_limit(network);
+        let authorization = network.credentials.git_authorization(&url.to_string());
+        let backend = http::reqwest::Options {
+            configure_request: Some(Box::new(move |request| {
+                *request.timeout_mut() = Some(timeout);
+                if let Some(authorization) = &authorization {
+                    let value = authorization.as_str().try_into()?;
+                    request.headers_mut().insert("authorization", value);
+                }
+                Ok(())
+            })),
+        };
+        options.backend = Some(Arc::new(Mutex::new(backend)));
+    }
+
+    connection.set_transport_options(options);
+    Ok(())
+}
+
+fn local_branch(repo: &Repository) -> Result<String> {
+    let name = repo.head_name()?.context("HEAD is detached")?;
+    let name = name.shorten().to_string();
+    if name.is_empty() {
+        bail!("HEAD is not on a branch");
+    }
+
+    Ok(name)
+}
+
+/// Returns whether the worktree differs from the index or `HEAD`, or has untracked
+/// files that are not ignored.
+fn is_modified(repo: &Repository) -> Result<bool> {
+    if repo.is_dirty()? {
+        return Ok(true);
+    }
+
+    let options = repo.dirwalk_options()?.emit_tracked(false);
+    let index = repo.index_or_empty()?;
+    let walk = repo.dirwalk_iter(index, None::<BString>, (&INTERRUPT).into(), options)?;
+
+    for item in walk {
+        if item?.entry.status == gix::dir::entry::Status::Untracked {
+            return Ok(true);
+        }
+    }
+
+    Ok(false)
+}
+
+fn is_ancestor(repo: &Repository, ancestor: ObjectId, commit: ObjectId) -> bool {
+    let Ok(walk) = repo.rev_walk([commit]).all() else {
+        return false;
+    };
+
+    walk.filter_map(|x| x.ok()).any(|x| x.id == ancestor)
+}
+
+fn tree_entries(repo: &Repository, commit: ObjectId) -> Result<HashMap<String, ObjectId>> {
+    let mut recorder = Recorder::default();
+    repo.find_commit(commit)?
+        .tree()?
+        .traverse()
+        .breadthfirst(&mut recorder)?;
+
+    Ok(recorder
+        .records
+        .into_iter()
+        .filter(|x| !x.mode.is_tree())
+        .map(|x| (x.filepath.to_string(), x.oid))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::short;
+    use super::super::testing::{self, git};
+    use super::*;
+
+    #[test]
+    fn clone_and_pull_bare_repository() {
+        let temp = tempfile::tempdir().unwrap();
+        let work = temp.path().join("work");
+        let remote = temp.path().join("remote");
+        let commit = testing::repo(&work, &[("demo.schema.yaml", "schema")]);
+
+        std::fs::create_dir_all(remote.join("foo")).unwrap();
+        let bare = remote.join("foo").join("bar");
+        git(
+            temp.path(),
+            &[
+                "clone",
+                "--quiet",
+                "--bare",
+                "work",
+                &bare.to_string_lossy(),
+            ],
+        );
+
+        let spec = "foo/bar".parse::<Spec>().unwrap();
+        let dir = temp.path().join("packages").join("foo").join("bar");
+        let fetcher = EmbeddedGitFetcher::new(&spec, &dir, &testing::config(&remote));
+
+        let (log, bar) = testing::log();
+        fetcher.fetch(&log).unwrap();
+        let read = |path: &str| std::fs::read_to_string(dir.join(path)).unwrap();
+        assert_eq!(read("demo.schema.yaml"), "schema");
+        assert_eq!(fetcher.head().unwrap(), commit);
+        assert_eq!(fetcher.remote_head().unwrap(), commit);
+
+        fetcher.fetch(&log).unwrap();
+        assert_eq!(
+            bar.message(),
+            format!("Already up to date with main at {}", short(&commit))
+        );
+
+        let commit = testing::commit(&work, &[("demo.schema.yaml", "updated")]);
+        git(&work, &["push", "--quiet", &bare.to_string_lossy(), "main"]);
+
+        fetcher.fetch(&log).unwrap();
+        assert_eq!(read("demo.schema.yaml"), "updated");
+        assert_eq!(fetcher.head().unwrap(), commit);
+    }
+}
@@ -0,0 +1,194 @@
+use anyhow::Error;
+
+/// Kinds of failed network operations, told apart by the error messages of git and
+/// the HTTP status codes to give actionable advice.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The repository or file does not exist, or is private
+    NotFound,
+    /// Credentials are required or rejected
+    AuthRequired,
+    /// The branch does not exist in the repository
+    BranchMissing,
+    /// The host cannot be reached, or responds with a temporary error
+    Unreachable,
+    /// The operation exceeded `fetcher.timeout`
+    TimedOut,
+}
+
+/// Message of the errors of operations stopped at the time limit, e.g. by
+/// [`crate::progress::wait`].
+const TIMED_OUT: &str = "timed out after";
+
+const BRANCH_MISSING: &[&str] = &[
+    "remote branch",
+    "couldn't find remote ref",
+    "didn't have any ref that matched",
+];
+
+const AUTH_REQUIRED: &[&str] = &[
+    "authentication failed",
+    "authentication required",
+    "could not read username",
+    "could not read password",
+    "terminal prompts disabled",
+    "permission denied (publickey)",
+    "returned error: 401",
+    "returned error: 403",
+    "http status 401",
+    "http status 403",
+];
+
+const NOT_FOUND: &[&str] = &[
+    "not found",
+    "does not appear to be a git repository",
+    "is a valid git directory",
+    "does not exist",
+    "returned error: 404",
+    "http status 404",
+];
+
+const UNREACHABLE: &[&str] = &[
+    "error sending request",
+    "could not resolve",
+    "name resolution",
+    "dns failed",
+    "failed to connect",
+    "connection refused",
+    "connection reset",
+    "connection timed out",
+    "network is unreachable",
+    "no route to host",
+    "early eof",
+    "rpc failed",
+    "unexpected disconnect",
+    "the remote end hung up",
+    "http status 429",
+    "http status 5",
+];
+
+impl Failure {
+    pub fn classify(error: &Error) -> Option<Self> {
+        for cause in error.chain() {
+            match cause.downcast_ref::<ureq::Error>() {
+                Some(ureq::Error::Status(code, _)) => return Self::from_status(*code),
+                Some(ureq::Error::Transport(x)) if x.to_string().contains("timed out") => {
+                    return Some(Self::TimedOut)
+                }
+                Some(ureq::Error::Transport(_)) => return Some(Self::Unreachable),
+                None => {}
+            }
+        }
+
+        let message = format!("{error:#}").to_lowercase();
+        let matches = |patterns: &[&str]| patterns.iter().any(|x| message.contains(x));
+
+        if message.contains(TIMED_OUT) {
+            Some(Self::TimedOut)
+        } else if matches(BRANCH_MISSING) {
+            Some(Self::BranchMissing)
+        } else if matches(AUTH_REQUIRED) {
+            Some(Self::AuthRequired)
+        } else if matches(NOT_FOUND) {
+            Some(Self::NotFound)
+        } else if matches(UNREACHABLE) {
+            Some(Self::Unreachable)
+        } else {
+            None
+        }
+    }
+
+    fn from_status(code: u16) -> Option<Self> {
+        match code {
+            401 | 403 => Some(Self::AuthRequired),
+            404 | 410 => Some(Self::NotFound),
+            408 | 429 | 500.. => Some(Self::Unreachable),
+            _ => None,
+        }
+    }
+
+    /// Returns whether trying again may succeed.
+    pub fn is_transient(self) -> bool {
+        matches!(self, Self::Unreachable | Self::TimedOut)
+    }
+
+    /// Returns what went wrong and what may fix it.
+    pub fn advice(self, url: &str) -> String {
+        match self {
+            Self::NotFound => format!(
+                "{url} is not found; check the name of the package and the mirrors, or \
+                 whether it is private"
+            ),
+            Self::AuthRequired => format!(
+                "{url} requires authentication; set a token of the host in `fetcher.tokens`, \
+                 or fetch it over SSH"
+            ),
+            Self::BranchMissing => format!("the branch is not found in {url}"),
+            Self::Unreachable => format!(
+                "{url} is unreachable; check the network connection or the mirrors, or \
+                 install the cached packages with --offline"
+            ),
+            Self::TimedOut => {
+                format!("{url} timed out; increase `fetcher.timeout` if the connection is slow")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    fn classify(message: &str) -> Option<Failure> {
+        Failure::classify(&anyhow!("{message}").context("failed to fetch foo/bar"))
+    }
+
+    fn classify_status(code: u16) -> Option<Failure> {
+        let response = ureq::Response::new(code, "", "").unwrap();
+        Failure::classify(&ureq::Error::Status(code, response).into())
+    }
+
+    #[test]
+    fn classify_git_errors() {
+        assert_eq!(
+            classify("fatal: repository 'https://github.com/foo/bar/' not found"),
+            Some(Failure::NotFound)
+        );
+        assert_eq!(
+            classify("fatal: Remote branch dev not found in upstream origin"),
+            Some(Failure::BranchMissing)
+        );
+        assert_eq!(
+            classify("fatal: could not read Username for 'https://github.com': terminal prompts disabled"),
+            Some(Failure::AuthRequired)
+        );
+        assert_eq!(
+            classify("fatal: unable to access: Could not resolve host: github.com"),
+            Some(Failure::Unreachable)
+        );
+        assert_eq!(
+            classify("`git` timed out after 600s"),
+            Some(Failure::TimedOut)
+        );
+        assert_eq!(classify("fatal: bad object HEAD"), None);
+    }
+
+    #[test]
+    fn classify_http_status() {
+        assert_eq!(classify_status(401), Some(Failure::AuthRequired));
+        assert_eq!(classify_status(404), Some(Failure::NotFound));
+        assert_eq!(classify_status(429), Some(Failure::Unreachable));
+        assert_eq!(classify_status(503), Some(Failure::Unreachable));
+        assert_eq!(classify_status(400), None);
+    }
+
+    #[test]
+    fn transient_failures() {
+        assert!(Failure::Unreachable.is_transient());
+        assert!(Failure::TimedOut.is_transient());
+        assert!(!Failure::NotFound.is_transient());
+        assert!(!Failure::AuthRequired.is_transient());
+    }
+}
@@ -0,0 +1,769 @@
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+
+use anyhow::{bail, Context};
+use owo_colors::OwoColorize;
+
+use super::{
+    check_checkout_dir, describe_changes, discard_broken, discard_failed_clone, empty_dir, exclude,
+    excluded, fallback, has_submodules, is_same_url, log_local_changes, log_up_to_date,
+    sparse_patterns, time_limit, tracking_refspec, uses_lfs, Changes, Fetcher, LocalChanges,
+};
+use crate::config::{FetcherConfig, Network, Signatures};
+use crate::progress::{self, Log};
+use crate::spec::Spec;
+use crate::Result;
+
+/// Fetches packages with the `git` command line.
+#[derive(Debug)]
+pub struct GitFetcher {
+    /// Candidate URLs of the repository, tried in order
+    urls: Vec<String>,
+    dir: PathBuf,
+    branch: Option<String>,
+    local_changes: LocalChanges,
+    network: Network,
+    /// Whether to keep a partial clone with only the files to be installed checked out
+    sparse: bool,
+}
+
+impl Fetcher for GitFetcher {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn url(&self) -> Option<String> {
+        self.urls.first().cloned()
+    }
+
+    fn fetch(&self, log: &Log) -> Result {
+        discard_broken(log, &self.dir, self.check())?;
+
+        if self.dir.join(".git").is_dir() {
+            self.repair_origin(log)?;
+            self.pull(log)
+        } else {
+            self.clone(log)
+        }
+    }
+
+    fn head(&self) -> Result<String> {
+        self.output("git", &["rev-parse", "HEAD"])
+    }
+
+    fn remote_head(&self) -> Result<String> {
+        Ok(self.remote_tip(&Log::Stdout)?.1)
+    }
+
+    /// Checks out a commit, fetching it if it is not available in the shallow clone.
+    /// Uncommitted changes are only stashed or discarded.
+    fn checkout(&self, commit: &str) -> Result {
+        let status = self.output("git", &["status", "--porcelain", "--ignore-submodules"])?;
+        if !status.is_empty() {
+            match self.local_changes {
+                LocalChanges::Discard => {}
+                LocalChanges::Stash => {
+                    let message = "rimeka: local changes before rolling back";
+                    let args = ["stash", "push", "--include-untracked", "--message", message];
+                    self.call(&Log::Stdout, &args)?;
+                }
+                _ => bail!(
+                    "{} has uncommitted changes; use --local-changes=stash or discard to roll \
+                     back over them",
+                    self.dir.display()
+                ),
+            }
+        }
+
+        let object = format!("{commit}^{{commit}}");
+        if self.output("git", &["cat-file", "-e", &object]).is_err() {
+            fallback(&Log::Stdout, &self.urls, &self.network, |url| {
+                self.call_remote(&Log::Stdout, &["fetch", url, commit, "--depth=1"])
+            })?;
+        }
+
+        self.call(&Log::Stdout, &["clean", "-xdf"])?;
+        self.call(&Log::Stdout, &["reset", "--hard", commit])?;
+        self.fetch_contents(&Log::Stdout)?;
+
+        println!();
+        Ok(())
+    }
+
+    /// Deepens the shallow clone until `from` is reachable from `to`.
+    fn changes(&self, from: &str, to: &str) -> Result<Option<Changes>> {
+        let branch = self.local_branch()?;
+        let refspec = tracking_refspec(&branch);
+
+        for depth in [50, 500, 5000] {
+            if self.is_ancestor(from, to) {
+                break;
+            }
+
+            if self.output("git", &["rev-parse", "--is-shallow-repository"])? != "true" {
+                return Ok(None);
+            }
+
+            let depth = format!("--deepen={depth}");
+            fallback(&Log::Stdout, &self.urls, &self.network, |url| {
+                self.remote_output(&["fetch", url, &refspec, &depth])
+            })?;
+        }
+
+        if !self.is_ancestor(from, to) {
+            return Ok(None);
+        }
+
+        let range = format!("{from}..{to}");
+        let log = self.output("git", &["log", "--format=%h %s", &range])?;
+        let files = self.output("git", &["diff", "--name-only", from, to])?;
+
+        Ok(Some(Changes {
+            log: log.lines().map(String::from).collect(),
+            files: files.lines().map(String::from).collect(),
+        }))
+    }
+
+    /// Checks out the files matching the patterns along with the recipes, fetching
+    /// those missing from the partial clone.
+    fn narrow(&self, log: &Log, patterns: &[String]) -> Result {
+        if !self.sparse {
+            return Ok(());
+        }
+
+        let patterns = sparse_patterns(patterns);
+        let mut args = vec!["sparse-checkout", "set", "--no-cone", "--"];
+        args.extend(patterns.iter().map(String::as_str));
+        self.call_remote(log, &args)
+    }
+
+    fn ignore(&self, path: &str) -> Result {
+        exclude(&self.dir, path)
+    }
+
+    /// Verifies against only the configured keys, in a keyring and an allowed signers
+    /// file of their own rather than those of the user.
+    fn verify(&self, signatures: &Signatures) -> Result {
+        let status = self.output("git", &["status", "--porcelain", "--untracked-files=no"])?;
+        if !status.is_empty() {
+            bail!(
+                "{} has uncommitted changes, which are not signed",
+                self.dir.display()
+            );
+        }
+
+        // Ignored files are not signed either. Those downloaded by the recipes are let
+        // through unverified, as only their URLs in the signed recipes are covered
+        let downloaded = excluded(&self.dir);
+        let others = self.output("git", &["ls-files", "--others", "-z"])?;
+        let unsigned = others
+            .split('\0')
+            .filter(|x| !x.is_empty() && !downloaded.iter().any(|y| y == x))
+            .collect::<Vec<_>>();
+        if !unsigned.is_empty() {
+            bail!(
+                "{} has files added to it, which are not signed: {}",
+                self.dir.display(),
+                unsigned.join(", ")
+            );
+        }
+
+        let home = self.dir.join(".git").join("rimeka-keys");
+        if home.exists() {
+            std::fs::remove_dir_all(&home)?;
+        }
+        std::fs::create_dir_all(&home)?;
+
+        let result = self.verify_with(signatures, &home);
+        std::fs::remove_dir_all(&home)?;
+        result
+    }
+}
+
+impl GitFetcher {
+    pub fn new(spec: &Spec, dir: &Path, config: &FetcherConfig) -> Self {
+        Self {
+            urls: config.mirrors.urls(&spec.url()),
+            dir: dir.to_path_buf(),
+            branch: spec.branch().map(|x| x.to_string()),
+            local_changes: config.local_changes,
+            network: config.network.clone(),
+            sparse: config.sparse,
+        }
+    }
+
+    fn clone(&self, log: &Log) -> Result {
+        let result = fallback(log, &self.urls, &self.network, |url| {
+            empty_dir(&self.dir)?;
+
+            let dir = self.dir.to_string_lossy();
+            let mut args = vec!["clone", url, &dir, "--depth=1"];
+            if let Some(branch) = &self.branch {
+                args.extend(["--branch", branch]);
+            }
+            if self.sparse {
+                // Files are checked out below, as the recipes determine which are needed
+                args.extend(["--filter=blob:none", "--no-checkout"]);
+            }
+
+            self.call_remote(log, &args)
+        });
+        discard_failed_clone(&self.dir, result)?;
+
+        if self.sparse {
+            // The index of a clone without checkout is empty, leaving nothing to narrow
+            self.narrow(log, &[])?;
+            self.call_remote(log, &["reset", "--hard", "HEAD"])?;
+        }
+
+        self.fetch_contents(log)?;
+        log.println("");
+        Ok(())
+    }
+
+    fn pull(&self, log: &Log) -> Result {
+        let config = self.output("git", &["config", "core.sparseCheckout"]);
+        if !self.sparse && config.is_ok_and(|x| x == "true") {
+            self.call_remote(log, &["sparse-checkout", "disable"])?;
+        }
+
+        let (branch, tip) = self.remote_tip(log)?;
+        let upstream = format!("origin/{branch}");
+
+        // Untracked files are local changes too, except the downloaded ones, which are
+        // ignored. Submodules are not, as they are checked out again after fetching
+        let status = self.output("git", &["status", "--porcelain", "--ignore-submodules"])?;
+        let modified = !status.is_empty();
+
+        let head = self.head()?;
+        if !modified && head == tip && self.local_branch()? == branch {
+            log_up_to_date(log, &branch, &tip);
+            // Submodules may be left empty by another backend or an interrupted fetch
+            if self.is_missing_contents() {
+                self.fetch_contents(log)?;
+            }
+            log.println("");
+            return Ok(());
+        }
+
+        let range = format!("{upstream}..HEAD");
+        let commits = self
+            .output("git", &["rev-list", "--count", &range])
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let mode = match modified || commits > 0 {
+            true => self.local_changes,
+            false => LocalChanges::Discard,
+        };
+
+        let changes = describe_changes(modified, commits);
+
+        match mode {
+            LocalChanges::Refuse => bail!(
+                "{} has {changes}; use --local-changes to stash, rebase or discard them",
+                self.dir.display()
+            ),
+            LocalChanges::Stash if commits > 0 => {
+                bail!("local commits can only be kept with --local-changes=rebase")
+            }
+            LocalChanges::Rebase if self.local_branch()? != branch => {
+                bail!("local changes are not on the branch `{branch}`")
+            }
+            _ => {}
+        }
+
+        let stashed = modified && matches!(mode, LocalChanges::Stash | LocalChanges::Rebase);
+        if stashed {
+            let message = "rimeka: local changes before fetching";
+            let args = ["stash", "push", "--include-untracked", "--message", message];
+            self.call(log, &args)?;
+        }
+
+        let previous = self.output("git", &["rev-parse", &upstream]);
+
+        self.call(log, &["clean", "-xdf"])?;
+        self.call(log, &["reset", "--hard", "HEAD"])?;
+        fallback(log, &self.urls, &self.network, |url| {
+            let refspec = tracking_refspec(&branch);
+            let mut args = vec!["fetch", url, &refspec, "--depth=1"];
+            if self.sparse {
+                args.push("--filter=blob:none");
+            }
+
+            self.call_remote(log, &args)
+        })?;
+
+        if mode == LocalChanges::Rebase && commits > 0 {
+            let previous = previous?;
+            let rebase = ["rebase", "--onto", &upstream, &previous, &branch];
+            if let Err(e) = self.call(log, &rebase) {
+                self.call(log, &["rebase", "--abort"])?;
+                if stashed {
+                    self.call(log, &["stash", "pop"])?;
+                }
+                return Err(e.context("local commits conflict with the upstream"));
+            }
+        } else {
+            self.call(log, &["switch", "-C", &branch, "--track", &upstream])?;
+        }
+
+        match mode {
+            LocalChanges::Stash => log_local_changes(log, "stashed, see `git stash list`"),
+            LocalChanges::Rebase => {
+                if stashed {
+                    self.pop_stash(log)?;
+                }
+                log_local_changes(log, &format!("{changes} kept"));
+            }
+            LocalChanges::Discard if !changes.is_empty() => {
+                log_local_changes(log, &format!("{changes} discarded"));
+            }
+            _ => {}
+        }
+
+        if self.head()? != head || self.is_missing_contents() {
+            self.fetch_contents(log)?;
+        }
+        log.println("");
+        Ok(())
+    }
+
+    /// Reapplies the local changes stashed before fetching. If they conflict with the
+    /// upstream, the checkout is reset to the fetched revision and the changes are left
+    /// in the stash, as when rebasing the local commits fails.
+    fn pop_stash(&self, log: &Log) -> Result {
+        let stash = self.output("git", &["rev-parse", "--short", "stash@{0}"])?;
+        let Err(e) = self.call(log, &["stash", "pop"]) else {
+            return Ok(());
+        };
+
+        // Untracked files restored from the stash are removed too, unlike ignored ones
+        self.call(log, &["reset", "--hard", "HEAD"])?;
+        self.call(log, &["clean", "-df"])?;
+        Err(e.context(format!(
+            "the local changes conflict with the upstream; they are kept in stash@{{0}} \
+             ({stash}), see `git stash show -p stash@{{0}}`"
+        )))
+    }
+
+    /// Fetches the submodules and the Git LFS objects of the checked out revision, which
+    /// a shallow clone leaves empty.
+    fn fetch_contents(&self, log: &Log) -> Result {
+        let submodules = has_submodules(&self.dir);
+        let lfs = uses_lfs(&self.dir);
+        if !submodules && !lfs {
+            return Ok(());
+        }
+
+        if submodules {
+            let args = [
+                "submodule",
+                "update",
+                "--init",
+                "--recursive",
+                "--depth=1",
+                "--force",
+            ];
+            self.call_remote(log, &args)
+                .context("failed to fetch the submodules")?;
+        }
+
+        if lfs && self.output("git", &["lfs", "version"]).is_err() {
+            log.println(format!(
+                "- {} git-lfs is not installed, Git LFS files are left as pointers",
+                "Warning:".yellow()
+            ));
+        } else if lfs {
+            // Objects are only downloaded below, within the time limit and with the token
+            self.call(log, &["lfs", "install", "--local", "--skip-smudge"])?;
+            self.call_remote(log, &["lfs", "pull"])
+                .context("failed to fetch the Git LFS objects")?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a submodule is not checked out, or a Git LFS object is not
+    /// downloaded, which are only checked locally.
+    fn is_missing_contents(&self) -> bool {
+        let submodules = has_submodules(&self.dir)
+            && self
+                .output("git", &["submodule", "status", "--recursive"])
+                .map_or(true, |x| x.lines().any(|x| x.starts_with('-')));
+
+        // Objects not downloaded are listed with `-` instead of `*`
+        let lfs = uses_lfs(&self.dir)
+            && self
+                .output("git", &["lfs", "ls-files"])
+                .is_ok_and(|x| x.lines().any(|x| x.contains(" - ")));
+
+        submodules || lfs
+    }
+
+    /// Returns why the existing checkout is unusable, e.g. after an interrupted clone.
+    fn check(&self) -> Option<String> {
+        if let Some(problem) = check_checkout_dir(&self.dir) {
+            return Some(problem);
+        }
+
+        if !self.dir.join(".git").is_dir() {
+            return None;
+        }
+
+        // Pin the repository to keep git from falling back to a parent directory
+        let git = |args: &[&str]| self.output("git", &[&["--git-dir=.git"], args].concat());
+
+        if git(&["rev-parse", "--verify", "HEAD"]).is_err() {
+            return Some("the repository is corrupted".to_string());
+        }
+
+        None
+    }
+
+    /// Points the remote `origin` to the repository, e.g. after the mirrors changed. It
+    /// only tracks the fetched branch, as the URLs are given explicitly when fetching.
+    fn repair_origin(&self, log: &Log) -> Result {
+        let Some(expected) = self.urls.first() else {
+            return Ok(());
+        };
+
+        let url = self.output("git", &["config", "--get", "remote.origin.url"]);
+        let args = match &url {
+            Ok(url) if self.urls.iter().any(|x| is_same_url(x, url)) => return Ok(()),
+            Ok(_) => ["remote", "set-url", "origin", expected],
+            Err(_) => ["remote", "add", "origin", expected],
+        };
+
+        self.call(log, &args)?;
+        log.println(format!("- {} `origin` set to {expected}", "Remote:".cyan()));
+        Ok(())
+    }
+
+    fn verify_with(&self, signatures: &Signatures, home: &Path) -> Result {
+        for key in &signatures.gpg {
+            let mut child = Command::new("gpg")
+                .args(["--homedir", &home.to_string_lossy(), "--batch", "--import"])
+                .stdin(Stdio::piped())
+                .stdout(Stdio::null())
+                .stderr(Stdio::piped())
+                .spawn()
+                .context("failed to run `gpg`")?;
+            child
+                .stdin
+                .take()
+                .context("stdin is not captured")?
+                .write_all(key.as_bytes())?;
+
+            let output = child.wait_with_output()?;
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            output.status.exit_ok().with_context(|| {
+                format!("failed to import a GPG key: {}", progress::summary(&stderr))
+            })?;
+        }
+
+        let signers = home.join("allowed_signers");
+        let lines = signatures.ssh.iter().map(|x| format!("* {x}\n"));
+        std::fs::write(&signers, lines.collect::<String>())?;
+
+        let verify = |args: &[&str]| {
+            Command::new("git")
+                .current_dir(&self.dir)
+                .env("GNUPGHOME", home)
+                .arg("-c")
+                .arg(format!("gpg.ssh.allowedSignersFile={}", signers.display()))
+                .args(args)
+                .output()
+        };
+
+        let output = verify(&["verify-commit", "HEAD"])?;
+        if output.status.success() {
+            return Ok(());
+        }
+
+        // Tags are not fetched along with the branch
+        self.fetch_tags().ok();
+
+        let tags = self.output("git", &["tag", "--points-at", "HEAD"])?;
+        for tag in tags.lines() {
+            if verify(&["verify-tag", tag])?.status.success() {
+                return Ok(());
+            }
+        }
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let reason = match progress::summary(&stderr) {
+            "" => "no signature found",
+            x => x,
+        };
+        bail!(
+            "{} is not signed with any of the keys in `signatures`: {reason}",
+            self.head()?
+        )
+    }
+
+    /// Fetches the annotated tags pointing to `HEAD`, which may be signed.
+    fn fetch_tags(&self) -> Result {
+        let head = self.head()?;
+
+        fallback(&Log::Stdout, &self.urls, &self.network, |url| {
+            let refs = self.remote_output(&["ls-remote", "--tags", url])?;
+            let refspecs = refs
+                .lines()
+                .filter_map(|line| line.split_once('\t'))
+                .filter(|(commit, _)| *commit == head)
+                .filter_map(|(_, name)| name.strip_suffix("^{}"))
+                .map(|name| format!("+{name}:{name}"))
+                .collect::<Vec<_>>();
+
+            if refspecs.is_empty() {
+                return Ok(String::new());
+            }
+
+            let mut args = vec!["fetch", url, "--depth=1"];
+            args.extend(refspecs.iter().map(String::as_str));
+            self.remote_output(&args)
+        })?;
+
+        Ok(())
+    }
+
+    fn local_branch(&self) -> Result<String> {
+        self.output("git", &["symbolic-ref", "--short", "HEAD"])
+    }
+
+    fn is_ancestor(&self, ancestor: &str, commit: &str) -> bool {
+        self.output("git", &["merge-base", "--is-ancestor", ancestor, commit])
+            .is_ok()
+    }
+
+    /// Returns the branch to be fetched and its latest commit. Without a branch given,
+    /// the remote `HEAD` tells both the default branch and its commit at once.
+    fn remote_tip(&self, log: &Log) -> Result<(String, String)> {
+        let refname = match &self.branch {
+            Some(branch) => format!("refs/heads/{branch}"),
+            None => "HEAD".to_string(),
+        };
+
+        let output = fallback(log, &self.urls, &self.network, |url| {
+            self.remote_output(&["ls-remote", "--symref", url, &refname])
+        })?;
+
+        let branch = match &self.branch {
+            Some(branch) => branch.clone(),
+            None => output
+                .lines()
+                .find_map(|line| {
+                    line.strip_prefix("ref: refs/heads/")
+                        .and_then(|x| x.strip_suffix("\tHEAD"))
+                        .map(str::trim)
+                        .map(String::from)
+                })
+                .context("unexpected output from `git ls-remote`")?,
+        };
+
+        let commit = output
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .find(|(commit, name)| *name == refname && !commit.starts_with("ref: "))
+            .map(|(commit, _)| commit.to_string())
+            .with_context(|| format!("branch `{branch}` not found in the remote"))?;
+
+        Ok((branch, commit))
+    }
+
+    fn call(&self, log: &Log, args: &[&str]) -> Result {
+        log.run(&mut self.command(args), None)
+    }
+
+    /// Returns a git command with the tokens of the hosts of the repository, as any
+    /// command may talk to the remote, e.g. fetching missing files of a partial clone.
+    fn command(&self, args: &[&str]) -> Command {
+        let mut command = Command::new("git");
+        command.current_dir(&self.dir).args(args);
+        // Fail instead of asking for a password, which is hidden behind the progress
+        command.env("GIT_TERMINAL_PROMPT", "0");
+
+        // Passed in the environment to keep them out of the URLs and the process list
+        let mut count = std::env::var("GIT_CONFIG_COUNT")
+            .ok()
+            .and_then(|x| x.parse::<usize>().ok())
+            .unwrap_or(0);
+
+        let mut hosts = Vec::new();
+        for url in &self.urls {
+            let Some(authorization) = self.network.credentials.git_authorization(url) else {
+                continue;
+            };
+
+            // Scoped to the host, as submodules and Git LFS live at other URLs
+            let host = url.splitn(4, '/').take(3).collect::<Vec<_>>().join("/");
+            if hosts.contains(&host) {
+                continue;
+            }
+
+            command.env(
+                format!("GIT_CONFIG_KEY_{count}"),
+                format!("http.{host}/.extraHeader"),
+            );
+            command.env(
+                format!("GIT_CONFIG_VALUE_{count}"),
+                format!("Authorization: {authorization}"),
+            );
+            count += 1;
+            hosts.push(host);
+        }
+
+        if !hosts.is_empty() {
+            command.env("GIT_CONFIG_COUNT", count.to_string());
+        }
+
+        command
+    }
+
+    /// Runs a git command talking to a remote, killing it after the time limit.
+    fn call_remote(&self, log: &Log, args: &[&str]) -> Result {
+        log.run(&mut self.command(args), Some(time_limit(&self.network)))
+    }
+
+    /// Returns the output of a git command talking to a remote, killing it after the
+    /// time limit.
+    fn remote_output(&self, args: &[&str]) -> Result<String> {
+        let mut child = self
+            .command(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        let stdout = read_all(child.stdout.take().context("stdout is not captured")?);
+        let stderr = read_all(child.stderr.take().context("stderr is not captured")?);
+
+        let status =
+            progress::wait(&mut child, Some(time_limit(&self.network))).context("`git` failed")?;
+        let stderr = stderr.join().unwrap_or_default();
+        status
+            .exit_ok()
+            .with_context(|| format!("`git` failed: {}", progress::summary(&stderr)))?;
+
+        Ok(stdout.join().unwrap_or_default())
+    }
+
+    /// Returns the output of a command in the checkout, with the command line and the
+    /// directory in the error to tell which one failed.
+    fn output(&self, command: &str, args: &[&str]) -> Result<String> {
+        let line = format!("`{command} {}` in {}", args.join(" "), self.dir.display());
+        let output = Command::new(command)
+            .current_dir(&self.dir)
+            .args(args)
+            .output()
+            .with_context(|| format!("failed to run {line}"))?;
+
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        output
+            .status
+            .exit_ok()
+            .with_context(|| match progress::summary(&stderr) {
+                "" => format!("{line} failed"),
+                summary => format!("{line} failed: {summary}"),
+            })?;
+
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+}
+
+/// Reads the whole output of a command in the background, as it may be held open by
+/// descendants of a killed command.
+fn read_all(mut output: impl Read + Send + 'static) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut buffer = Vec::new();
+        output.read_to_end(&mut buffer).ok();
+        String::from_utf8_lossy(&buffer).trim().to_string()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::{self, git};
+    use super::*;
+
+    /// Creates a bare repository of `foo/bar` with the files committed, returning the
+    /// temporary directory containing it and the commit.
+    fn remote(files: &[(&str, &str)]) -> (tempfile::TempDir, String) {
+        let temp = tempfile::tempdir().unwrap();
+        let bare = temp.path().join("remote").join("foo").join("bar");
+        std::fs::create_dir_all(&bare).unwrap();
+        git(&bare, &["init", "--quiet", "--bare"]);
+
+        let commit = testing::repo(&temp.path().join("work"), files);
+        push(temp.path());
+        (temp, commit)
+    }
+
+    /// Commits the files to the bare repository, returning the commit.
+    fn update(temp: &Path, files: &[(&str, &str)]) -> String {
+        let commit = testing::commit(&temp.join("work"), files);
+        push(temp);
+        commit
+    }
+
+    fn push(temp: &Path) {
+        let bare = temp.join("remote").join("foo").join("bar");
+        let args = [
+            "push",
+            "--quiet",
+            "--force",
+            &bare.to_string_lossy(),
+            "main",
+        ];
+        git(&temp.join("work"), &args);
+    }
+
+    fn fetcher(temp: &Path, target: &str, config: &FetcherConfig) -> GitFetcher {
+        let spec = target.parse::<Spec>().unwrap();
+        let dir = temp.join("packages").join(spec.checkout_path());
+        GitFetcher::new(&spec, &dir, config)
+    }
+
+    #[test]
+    fn clone_missing_branch() {
+        let (temp, _) = remote(&[("a.txt", "a")]);
+        let config = testing::config(&temp.path().join("remote"));
+        let fetcher = fetcher(temp.path(), "foo/bar@nope", &config);
+
+        let (log, _) = testing::log();
+        let error = fetcher.fetch(&log).unwrap_err();
+        assert!(format!("{error:#}").contains("nope"), "{error:#}");
+        assert!(!fetcher.dir.exists());
+    }
+
+    #[test]
+    fn rebase_conflicting_changes() {
+        let (temp, _) = remote(&[("a.txt", "a")]);
+        let mut config = testing::config(&temp.path().join("remote"));
+        config.local_changes = LocalChanges::Rebase;
+        let fetcher = fetcher(temp.path(), "foo/bar", &config);
+
+        let (log, _) = testing::log();
+        fetcher.fetch(&log).unwrap();
+
+        std::fs::write(fetcher.dir.join("a.txt"), "local").unwrap();
+        let commit = update(temp.path(), &[("a.txt", "upstream")]);
+
+        let error = fetcher.fetch(&log).unwrap_err();
+        assert!(format!("{error:#}").contains("stash@{0}"), "{error:#}");
+
+        let read = || std::fs::read_to_string(fetcher.dir.join("a.txt")).unwrap();
+        assert_eq!(read(), "upstream");
+        assert_eq!(fetcher.head().unwrap(), commit);
+        assert_eq!(git(&fetcher.dir, &["status", "--porcelain"]), "");
+
+        git(
+            &fetcher.dir,
+            &["checkout", "--quiet", "stash@{0}", "--", "a.txt"],
+        );
+        assert_eq!(read(), "local");
+    }
+}
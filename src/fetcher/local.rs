@@ -0,0 +1,70 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::bail;
+use owo_colors::OwoColorize;
+
+use super::{Changes, Fetcher};
+use crate::progress::Log;
+use crate::Result;
+
+/// Uses a directory on the local disk as the package, e.g. a working copy under
+/// development. Nothing is fetched or modified.
+#[derive(Debug)]
+pub struct LocalFetcher {
+    dir: PathBuf,
+}
+
+impl Fetcher for LocalFetcher {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn url(&self) -> Option<String> {
+        None
+    }
+
+    fn fetch(&self, log: &Log) -> Result {
+        if !self.dir.is_dir() {
+            bail!("local package not found: {}", self.dir.display());
+        }
+
+        log.println(format!("- {} {}", "Local:".cyan(), self.dir.display()));
+        Ok(())
+    }
+
+    /// Uses the commit of the working copy if it is a git repository.
+    fn head(&self) -> Result<String> {
+        let output = Command::new("git")
+            .current_dir(&self.dir)
+            .args(["rev-parse", "HEAD"])
+            .output();
+
+        match output {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8(output.stdout)?.trim().to_string())
+            }
+            _ => Ok("local".to_string()),
+        }
+    }
+
+    fn remote_head(&self) -> Result<String> {
+        self.head()
+    }
+
+    fn checkout(&self, _: &str) -> Result {
+        bail!("local packages cannot be checked out to other revisions")
+    }
+
+    fn changes(&self, _: &str, _: &str) -> Result<Option<Changes>> {
+        Ok(None)
+    }
+}
+
+impl LocalFetcher {
+    pub fn new(dir: &Path) -> Self {
+        Self {
+            dir: dir.to_path_buf(),
+        }
+    }
+}
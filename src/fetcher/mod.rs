@@ -0,0 +1,690 @@
+use std::cell::Cell;
+use std::fmt::{Debug, Display};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Context};
+use owo_colors::OwoColorize;
+use path_clean::PathClean;
+
+use self::failure::Failure;
+use crate::config::{FetcherConfig, Network, Signatures};
+use crate::progress::Log;
+use crate::spec::{Origin, Spec};
+use crate::Result;
+
+pub use self::archive::{ArchiveFetcher, REVISION_FILE};
+#[cfg(feature = "embedded-git")]
+pub use self::embedded::EmbeddedGitFetcher;
+pub use self::git::GitFetcher;
+pub use self::local::LocalFetcher;
+pub use self::recipe::{record_revision, RecipeFetcher, DOWNLOADS_FILE};
+
+mod archive;
+#[cfg(feature = "embedded-git")]
+mod embedded;
+mod failure;
+mod git;
+mod local;
+mod recipe;
+#[cfg(test)]
+mod testing;
+
+/// A backend that materializes packages on the local disk.
+pub trait Fetcher: Debug {
+    /// Returns the directory containing the files of the package.
+    fn dir(&self) -> &Path;
+
+    /// Returns the URL fetched from first, or `None` if the package is not fetched
+    /// over the network.
+    fn url(&self) -> Option<String>;
+
+    /// Fetches the latest revision of the package, writing the progress to the log.
+    fn fetch(&self, log: &Log) -> Result;
+
+    /// Returns the revision of the fetched files.
+    fn head(&self) -> Result<String>;
+
+    /// Returns the latest revision available upstream, without fetching it.
+    fn remote_head(&self) -> Result<String>;
+
+    /// Switches the fetched files to a previously installed revision.
+    fn checkout(&self, revision: &str) -> Result;
+
+    /// Returns the changes between two revisions, or `None` if the history is unavailable.
+    fn changes(&self, from: &str, to: &str) -> Result<Option<Changes>>;
+
+    /// Narrows the fetched files to those matching any of the install patterns, if the
+    /// backend supports sparse checkouts.
+    fn narrow(&self, _log: &Log, _patterns: &[String]) -> Result {
+        Ok(())
+    }
+
+    /// Keeps a file created in the checkout by rimeka, e.g. downloaded by a recipe, from
+    /// being taken for a local change.
+    fn ignore(&self, _path: &str) -> Result {
+        Ok(())
+    }
+
+    /// Verifies that the fetched revision, or a tag pointing to it, is signed with one of
+    /// the keys.
+    fn verify(&self, _signatures: &Signatures) -> Result {
+        bail!("signatures can only be verified by the git backend")
+    }
+}
+
+#[derive(Debug)]
+pub struct Changes {
+    pub log: Vec<String>,
+    pub files: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Backend {
+    #[default]
+    Git,
+    EmbeddedGit,
+    Archive,
+    Local,
+}
+
+impl Display for Backend {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Backend::Git => "git",
+            Backend::EmbeddedGit => "embedded-git",
+            Backend::Archive => "archive",
+            Backend::Local => "local",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Backend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "git" => Ok(Self::Git),
+            "embedded-git" => Ok(Self::EmbeddedGit),
+            "archive" => Ok(Self::Archive),
+            "local" => Ok(Self::Local),
+            _ => bail!("unknown fetcher backend: {s}"),
+        }
+    }
+}
+
+/// What to do with the local changes in a cached checkout before fetching.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum LocalChanges {
+    /// Abort fetching the package
+    #[default]
+    Refuse,
+    /// Stash uncommitted changes, leaving them in `git stash list`
+    Stash,
+    /// Keep uncommitted changes and local commits on top of the fetched revision
+    Rebase,
+    /// Remove the changes
+    Discard,
+}
+
+impl Display for LocalChanges {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            LocalChanges::Refuse => "refuse",
+            LocalChanges::Stash => "stash",
+            LocalChanges::Rebase => "rebase",
+            LocalChanges::Discard => "discard",
+        };
+
+        f.write_str(name)
+    }
+}
+
+impl FromStr for LocalChanges {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(Self::Refuse),
+            "stash" => Ok(Self::Stash),
+            "rebase" => Ok(Self::Rebase),
+            "discard" => Ok(Self::Discard),
+            _ => bail!("unknown way to handle local changes: {s}"),
+        }
+    }
+}
+
+/// Creates the fetcher of a package, following the per-package, per-host and default
+/// backends in the config, in that order. Recipe files outside any repository are
+/// read from the local disk or downloaded.
+pub fn select(spec: &Spec, base: &Path, config: &FetcherConfig) -> Result<Box<dyn Fetcher>> {
+    let dir = base.join(spec.checkout_path()).clean();
+
+    match spec.origin() {
+        Origin::Repository => {}
+        Origin::Directory => return Ok(Box::new(LocalFetcher::new(&dir))),
+        Origin::Url => return Ok(Box::new(RecipeFetcher::new(spec, &dir, config))),
+    }
+
+    let source = config.packages.get(spec.repo());
+    let backend = source
+        .map(|x| x.backend)
+        .or_else(|| config.hosts.get(spec.host()).copied())
+        .unwrap_or(config.backend);
+
+    match backend {
+        Backend::Git => Ok(Box::new(GitFetcher::new(spec, &dir, config))),
+        #[cfg(feature = "embedded-git")]
+        Backend::EmbeddedGit => Ok(Box::new(EmbeddedGitFetcher::new(spec, &dir, config))),
+        #[cfg(not(feature = "embedded-git"))]
+        Backend::EmbeddedGit => bail!("rimeka is built without the `embedded-git` feature"),
+        Backend::Archive if spec.is_ssh() => {
+            bail!("the archive backend cannot fetch {} over SSH", spec.repo())
+        }
+        Backend::Archive => Ok(Box::new(ArchiveFetcher::new(spec, &dir, config))),
+        Backend::Local => {
+            let path = source
+                .and_then(|x| x.path.as_ref())
+                .with_context(|| format!("`path` is required for local package {}", spec.repo()))?;
+            Ok(Box::new(LocalFetcher::new(path)))
+        }
+    }
+}
+
+/// Returns why an existing directory cannot be a checkout, if it is not a git repository
+/// and not left empty by a failed clone.
+fn check_checkout_dir(dir: &Path) -> Option<String> {
+    if dir.join(".git").is_dir() || !dir.exists() {
+        return None;
+    }
+
+    match std::fs::read_dir(dir).map(|mut x| x.next().is_none()) {
+        Ok(true) => None,
+        _ => Some("not a git repository".to_string()),
+    }
+}
+
+/// Lists a file created in the checkout in `.git/info/exclude`, where it is ignored,
+/// and removed along with the other ignored files when fetching.
+fn exclude(dir: &Path, path: &str) -> Result {
+    if !dir.join(".git").is_dir() {
+        return Ok(());
+    }
+
+    let info = dir.join(".git").join("info");
+    let file = info.join("exclude");
+
+    let mut line = String::from("/");
+    for c in path.chars() {
+        if matches!(c, '\\' | '*' | '?' | '[') {
+            line.push('\\');
+        }
+        line.push(c);
+    }
+
+    let mut content = std::fs::read_to_string(&file).unwrap_or_default();
+    if content.lines().any(|x| x == line) {
+        return Ok(());
+    }
+
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(&line);
+    content.push('\n');
+
+    std::fs::create_dir_all(&info)?;
+    Ok(std::fs::write(file, content)?)
+}
+
+/// Returns the files created in the checkout by rimeka, as listed by [`exclude`].
+fn excluded(dir: &Path) -> Vec<String> {
+    let file = dir.join(".git").join("info").join("exclude");
+    let content = std::fs::read_to_string(file).unwrap_or_default();
+
+    let unescape = |line: &str| {
+        let mut path = String::new();
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            path.push(if c == '\\' {
+                chars.next().unwrap_or(c)
+            } else {
+                c
+            });
+        }
+        path
+    };
+
+    content
+        .lines()
+        .filter_map(|x| x.strip_prefix('/'))
+        .map(unescape)
+        .collect()
+}
+
+/// Creates an empty directory to clone into, removing what a failed clone left.
+fn empty_dir(dir: &Path) -> Result {
+    if dir.exists() {
+        std::fs::remove_dir_all(dir)?;
+    }
+
+    Ok(std::fs::create_dir_all(dir)?)
+}
+
+/// Removes the checkout directory after every URL failed to clone, so that it is not
+/// left empty and taken for a cached package.
+fn discard_failed_clone<T>(dir: &Path, result: Result<T>) -> Result<T> {
+    if result.is_err() && dir.exists() {
+        std::fs::remove_dir_all(dir)
+            .with_context(|| format!("failed to remove {}", dir.display()))?;
+    }
+
+    result
+}
+
+/// Returns whether the checkout has submodules, which a shallow clone leaves empty.
+fn has_submodules(dir: &Path) -> bool {
+    dir.join(".gitmodules").is_file()
+}
+
+/// Returns whether the checkout stores files in Git LFS, which are left as pointer
+/// files unless their objects are fetched.
+fn uses_lfs(dir: &Path) -> bool {
+    std::fs::read_to_string(dir.join(".gitattributes")).is_ok_and(|x| x.contains("filter=lfs"))
+}
+
+/// Warns about the submodules and Git LFS objects of a checkout, which the backend
+/// cannot fetch.
+fn warn_unfetched(log: &Log, dir: &Path, backend: Backend) {
+    let contents = [
+        (has_submodules(dir), "submodules"),
+        (uses_lfs(dir), "Git LFS objects"),
+    ];
+
+    for (_, what) in contents.into_iter().filter(|(present, _)| *present) {
+        log.println(format!(
+            "- {} {what} are not fetched by the {backend} backend; use the git backend",
+            "Warning:".yellow()
+        ));
+    }
+}
+
+/// Returns the abbreviated commit, or the revision as is if shorter, e.g. a tag.
+pub fn short(commit: &str) -> &str {
+    commit.get(..7).unwrap_or(commit)
+}
+
+/// Returns whether two URLs point to the same repository.
+fn is_same_url(a: &str, b: &str) -> bool {
+    let normalize = |x: &str| {
+        let x = x.trim_end_matches('/');
+        x.strip_suffix(".git").unwrap_or(x).to_string()
+    };
+
+    normalize(a) == normalize(b)
+}
+
+/// Patterns of the files always checked out in sparse checkouts, where the recipes
+/// and the submodules are found.
+const SPARSE_PATTERNS: &[&str] = &[
+    "/.gitmodules",
+    "/.gitattributes",
+    "/recipe.yaml",
+    "*.recipe.yaml",
+];
+
+/// Converts an install pattern to a sparse checkout pattern matching at least the same
+/// files. As `*` of install patterns also matches `/`, a pattern is narrowed only to
+/// the directory before its first wildcard, or to the last segment if it starts with
+/// one.
+fn sparse_pattern(pattern: &str) -> String {
+    let is_wildcard = |x: &str| x.contains(['*', '?', '[']);
+    let segments = pattern
+        .trim_start_matches("./")
+        .split('/')
+        .collect::<Vec<_>>();
+
+    match segments.iter().position(|x| is_wildcard(x)) {
+        None => format!("/{}", segments.join("/")),
+        Some(0) => segments.last().unwrap().to_string(),
+        Some(i) => format!("/{}/", segments[..i].join("/")),
+    }
+}
+
+/// Returns the sparse checkout patterns of the files always checked out, and of those
+/// matching the install patterns.
+fn sparse_patterns(patterns: &[String]) -> Vec<String> {
+    let mut converted = patterns
+        .iter()
+        .map(|x| sparse_pattern(x))
+        .collect::<Vec<_>>();
+    converted.sort();
+    converted.dedup();
+
+    let always = SPARSE_PATTERNS.iter().map(|x| x.to_string());
+    always.chain(converted).collect()
+}
+
+/// Refspec fetching a branch into its remote-tracking branch, as `git fetch origin` does.
+fn tracking_refspec(branch: &str) -> String {
+    format!("+refs/heads/{branch}:refs/remotes/origin/{branch}")
+}
+
+/// Removes a checkout that cannot be used, telling why, to clone it again.
+fn discard_broken(log: &Log, dir: &Path, problem: Option<String>) -> Result {
+    let Some(problem) = problem else {
+        return Ok(());
+    };
+
+    log.println(format!(
+        "- {} {problem}, cloning again",
+        "Repairing:".yellow()
+    ));
+    Ok(std::fs::remove_dir_all(dir)?)
+}
+
+/// Tells that the checkout is already at the latest commit of the branch.
+fn log_up_to_date(log: &Log, branch: &str, commit: &str) {
+    log.println(format!(
+        "Already up to date with {branch} at {}",
+        short(commit)
+    ));
+}
+
+/// Describes the local changes of a checkout, e.g. `uncommitted changes and 2 local
+/// commit(s)`, or returns an empty string if there are none.
+fn describe_changes(modified: bool, commits: usize) -> String {
+    [
+        modified.then(|| "uncommitted changes".to_string()),
+        (commits > 0).then(|| format!("{commits} local commit(s)")),
+    ]
+    .into_iter()
+    .flatten()
+    .collect::<Vec<_>>()
+    .join(" and ")
+}
+
+/// Tells what became of the local changes, e.g. `2 local commit(s) kept`.
+fn log_local_changes(log: &Log, what: &str) {
+    log.println(format!("- {} {what}", "Local changes:".cyan()));
+}
+
+thread_local! {
+    /// Deadline of the running [`fallback`], limiting the operations it runs
+    static DEADLINE: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Runs the operation with each URL in order until it succeeds, retrying transient
+/// failures with backoff, and returning the last error if none does. The attempts
+/// are all limited by the time limit, as each is by [`time_limit`].
+pub fn fallback<T>(
+    log: &Log,
+    urls: &[String],
+    network: &Network,
+    operation: impl FnMut(&str) -> Result<T>,
+) -> Result<T> {
+    if network.offline {
+        bail!(
+            "{} cannot be accessed offline",
+            urls.first().map_or("", |x| x.as_str())
+        );
+    }
+
+    // There is no deadline if the time limit is too long to be represented
+    let deadline = Instant::now().checked_add(network.timeout);
+    let previous = DEADLINE.replace(deadline);
+    let result = attempt(log, urls, network, deadline, operation);
+    DEADLINE.set(previous);
+
+    result.map_err(|e| match is_past(deadline, Duration::ZERO) {
+        true => e.context(format!(
+            "gave up after the time limit of {}s",
+            network.timeout.as_secs()
+        )),
+        false => e,
+    })
+}
+
+fn attempt<T>(
+    log: &Log,
+    urls: &[String],
+    network: &Network,
+    deadline: Option<Instant>,
+    mut operation: impl FnMut(&str) -> Result<T>,
+) -> Result<T> {
+    let mut error = anyhow!("no URL to fetch from");
+
+    for (i, url) in urls.iter().enumerate() {
+        if i > 0 {
+            if is_past(deadline, Duration::ZERO) {
+                break;
+            }
+            log.println(format!("- {} {}", "Falling back:".yellow(), url));
+        }
+
+        for attempt in 0..=network.retries {
+            if attempt > 0 {
+                // Waiting past the time limit would leave no time to retry
+                let delay = Duration::from_secs(1 << attempt.min(5));
+                if is_past(deadline, delay) {
+                    break;
+                }
+
+                log.println(format!(
+                    "- {} in {}s, {:#}",
+                    "Retrying:".yellow(),
+                    delay.as_secs(),
+                    error
+                ));
+                std::thread::sleep(delay);
+            }
+
+            let e = match operation(url) {
+                Ok(x) => return Ok(x),
+                Err(e) => e,
+            };
+
+            let failure = Failure::classify(&e);
+            error = match failure {
+                Some(failure) => e.context(failure.advice(url)),
+                None => e,
+            };
+
+            if !failure.is_some_and(Failure::is_transient) {
+                break;
+            }
+        }
+
+        if i + 1 < urls.len() {
+            log.println(format!("- {} {:#}", "Failed:".yellow(), error));
+        }
+    }
+
+    Err(error)
+}
+
+/// Returns whether the deadline will have passed after the delay.
+fn is_past(deadline: Option<Instant>, delay: Duration) -> bool {
+    deadline.is_some_and(|x| x.saturating_duration_since(Instant::now()) <= delay)
+}
+
+/// Returns the time limit of a network operation, shortened to the time left before
+/// the deadline of the running [`fallback`] if any.
+pub fn time_limit(network: &Network) -> Duration {
+    match DEADLINE.get() {
+        Some(deadline) => {
+            let left = deadline.saturating_duration_since(Instant::now());
+            network.timeout.min(left)
+        }
+        None => network.timeout,
+    }
+}
+
+/// Downloads a file over HTTP from the first URL that responds. The file is moved into
+/// place once complete, leaving the previous one if any when the download fails.
+pub fn download(log: &Log, urls: &[String], network: &Network, dest: &Path) -> Result {
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut part = dest.as_os_str().to_owned();
+    part.push(".part");
+    let part = PathBuf::from(part);
+
+    let result = fallback(log, urls, network, |url| {
+        let response = request(network, url).call()?;
+        let mut file = File::create(&part)?;
+        std::io::copy(&mut response.into_reader(), &mut file)?;
+        Ok(())
+    });
+
+    match result {
+        Ok(()) => Ok(std::fs::rename(&part, dest)?),
+        Err(e) => {
+            std::fs::remove_file(&part).ok();
+            Err(e)
+        }
+    }
+}
+
+/// Returns a GET request giving up after the time limit, with the token of the host
+/// if any.
+pub fn request(network: &Network, url: &str) -> ureq::Request {
+    let agent = ureq::AgentBuilder::new()
+        .timeout(time_limit(network))
+        .build();
+    let request = agent.get(url);
+
+    match network.credentials.token(url) {
+        Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+        None => request,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn fallback_within_time_limit() {
+        let network = Network {
+            retries: 5,
+            timeout: Duration::from_secs(3),
+            ..Default::default()
+        };
+        let urls = ["https://a.example.com", "https://b.example.com"].map(String::from);
+
+        let mut attempts = Vec::new();
+        let start = Instant::now();
+        let (log, _) = testing::log();
+        let error = fallback(&log, &urls, &network, |url| -> Result {
+            attempts.push((url.to_string(), time_limit(&network)));
+            std::thread::sleep(Duration::from_millis(500));
+            bail!("fatal: unable to access '{url}': Could not resolve host")
+        })
+        .unwrap_err();
+
+        // Retried once after 2s, with no time left to retry after 4s or fall back
+        assert!(start.elapsed() < Duration::from_secs(4));
+        assert_eq!(attempts.len(), 2);
+        assert!(attempts.iter().all(|(url, _)| url == &urls[0]));
+        assert!(attempts[0].1 > Duration::from_millis(2900));
+        assert!(attempts[1].1 <= Duration::from_millis(500));
+        assert!(
+            format!("{error:#}").contains("time limit of 3s"),
+            "{error:#}"
+        );
+        assert_eq!(time_limit(&network), network.timeout);
+    }
+
+    #[test]
+    fn sparse_pattern_of_file() {
+        assert_eq!(sparse_pattern("demo.schema.yaml"), "/demo.schema.yaml");
+        assert_eq!(sparse_pattern("./opencc/a.json"), "/opencc/a.json");
+    }
+
+    #[test]
+    fn sparse_pattern_narrowed_to_directory() {
+        assert_eq!(sparse_pattern("opencc/*.json"), "/opencc/");
+        assert_eq!(sparse_pattern("dicts/sub/[ab].yaml"), "/dicts/sub/");
+        assert_eq!(sparse_pattern("dicts/*/base.dict.yaml"), "/dicts/");
+    }
+
+    #[test]
+    fn sparse_pattern_starting_with_wildcard() {
+        assert_eq!(sparse_pattern("*.dict.yaml"), "*.dict.yaml");
+        assert_eq!(sparse_pattern("**/*.txt"), "*.txt");
+    }
+
+    #[test]
+    fn sparse_patterns_with_recipes() {
+        let patterns = ["b/*.txt", "a.txt", "b/*.yaml"].map(String::from);
+        assert_eq!(
+            &sparse_patterns(&patterns)[SPARSE_PATTERNS.len()..],
+            ["/a.txt", "/b/"]
+        );
+        assert_eq!(sparse_patterns(&[]), SPARSE_PATTERNS);
+    }
+
+    #[test]
+    fn short_revision() {
+        assert_eq!(short("0123456789abcdef"), "0123456");
+        assert_eq!(short("v1.0"), "v1.0");
+        assert_eq!(short(""), "");
+    }
+
+    #[test]
+    fn download_interrupted() {
+        // Promises more than it sends, then closes the connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/file", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Skip the request up to the empty line
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).is_ok_and(|x| x > 2) {}
+
+                let response = "HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\ntruncated";
+                stream.write_all(response.as_bytes()).ok();
+            }
+        });
+
+        let temp = tempfile::tempdir().unwrap();
+        let dest = temp.path().join("file");
+        let network = Network {
+            retries: 0,
+            ..Default::default()
+        };
+
+        let (log, _) = testing::log();
+        let urls = [url];
+        assert!(download(&log, &urls, &network, &dest).is_err());
+        assert!(!dest.exists());
+
+        std::fs::write(&dest, "previous").unwrap();
+        assert!(download(&log, &urls, &network, &dest).is_err());
+        assert_eq!(std::fs::read_to_string(&dest).unwrap(), "previous");
+        assert_eq!(std::fs::read_dir(temp.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn fallback_with_unbounded_time_limit() {
+        let network = Network {
+            timeout: Duration::MAX,
+            ..Default::default()
+        };
+        let urls = ["https://a.example.com".to_string()];
+
+        let (log, _) = testing::log();
+        let limit = fallback(&log, &urls, &network, |_| Ok(time_limit(&network)));
+        assert_eq!(limit.unwrap(), Duration::MAX);
+    }
+}
@@ -0,0 +1,150 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context};
+use owo_colors::OwoColorize;
+use path_clean::PathClean;
+use pathdiff::diff_paths;
+use saphyr::Yaml;
+use walkdir::WalkDir;
+
+use super::{download, Changes, Fetcher, REVISION_FILE};
+use crate::config::{FetcherConfig, Mirrors, Network};
+use crate::progress::Log;
+use crate::spec::Spec;
+use crate::Result;
+
+/// Name of the file recording the URL of each of the `download_files` of recipes, with
+/// the revision of the package it was downloaded at.
+pub const DOWNLOADS_FILE: &str = ".rimeka-downloads";
+
+/// Downloads a recipe file and the files it installs from the directory of its URL,
+/// e.g. a gist. The revision is a hash of the downloaded files, recorded as the archive
+/// backend does.
+#[derive(Debug)]
+pub struct RecipeFetcher {
+    /// URL of the directory containing the recipe file
+    url: String,
+    filename: String,
+    dir: PathBuf,
+    mirrors: Mirrors,
+    network: Network,
+}
+
+impl Fetcher for RecipeFetcher {
+    fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn url(&self) -> Option<String> {
+        let url = format!("{}/{}", self.url, self.filename);
+        self.mirrors.urls(&url).into_iter().next()
+    }
+
+    fn fetch(&self, log: &Log) -> Result {
+        self.download(log, &self.filename)?;
+
+        let path = self.dir.join(&self.filename);
+        let yaml = std::fs::read_to_string(&path).context("failed to read recipe file")?;
+        let docs = Yaml::load_from_str(&yaml).context("failed to parse recipe file")?;
+        let files = match docs.first().and_then(|x| x["install_files"].as_str()) {
+            Some(files) => shlex::split(files).context("syntax error in the file list")?,
+            None => Vec::new(),
+        };
+
+        // Only the files named as they are can be downloaded, as a URL cannot be listed
+        for file in files {
+            if file.contains(['*', '?', '[', '{']) {
+                log.println(format!(
+                    "- {} {} is a pattern, which cannot be downloaded from a URL",
+                    "Warning:".yellow(),
+                    file
+                ));
+                continue;
+            }
+
+            log.println(format!("- {} {}", "Downloading:".cyan(), file));
+            self.download(log, &file)?;
+        }
+
+        record_revision(&self.dir)
+    }
+
+    fn head(&self) -> Result<String> {
+        let revision = std::fs::read_to_string(self.dir.join(REVISION_FILE))
+            .context("the recipe file has not been downloaded")?;
+
+        Ok(revision.trim().to_string())
+    }
+
+    fn remote_head(&self) -> Result<String> {
+        bail!("recipe files cannot be checked for changes without downloading them")
+    }
+
+    fn checkout(&self, _: &str) -> Result {
+        bail!("recipe files downloaded from URLs cannot be checked out to other revisions")
+    }
+
+    fn changes(&self, _: &str, _: &str) -> Result<Option<Changes>> {
+        Ok(None)
+    }
+}
+
+impl RecipeFetcher {
+    pub fn new(spec: &Spec, dir: &Path, config: &FetcherConfig) -> Self {
+        Self {
+            url: spec.url(),
+            filename: spec.recipe().map(|x| x.filename()).unwrap_or_default(),
+            dir: dir.to_path_buf(),
+            mirrors: config.mirrors.clone(),
+            network: config.network.clone(),
+        }
+    }
+
+    /// Downloads a file relative to the URL of the directory.
+    fn download(&self, log: &Log, file: &str) -> Result {
+        let dest = self.dir.join(file).clean();
+        if file.is_empty() || !dest.starts_with(&self.dir) {
+            bail!("invalid path of file: {file}");
+        }
+
+        let urls = self.mirrors.urls(&format!("{}/{}", self.url, file));
+        download(log, &urls, &self.network, &dest)
+    }
+}
+
+/// Records the revision of the files downloaded into the directory, e.g. a recipe file
+/// of a repository, which is otherwise downloaded without being fetched.
+pub fn record_revision(dir: &Path) -> Result {
+    let revision = format!("{}\n", hash(dir)?);
+    Ok(std::fs::write(dir.join(REVISION_FILE), revision)?)
+}
+
+/// Hashes the paths and the contents of the downloaded files.
+fn hash(dir: &Path) -> Result<String> {
+    let mut files = WalkDir::new(dir)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_map(|x| x.ok())
+        .filter(|x| x.file_type().is_file())
+        .filter(|x| x.file_name() != REVISION_FILE && x.file_name() != DOWNLOADS_FILE);
+
+    files
+        .try_fold(FNV_OFFSET, |hash, entry| {
+            let relative =
+                diff_paths(entry.path(), dir).expect("walked path shouldn't be relative");
+            let hash = fnv1a(hash, relative.to_string_lossy().as_bytes());
+            let hash = fnv1a(hash, &[0]);
+            Ok(fnv1a(hash, &std::fs::read(entry.path())?))
+        })
+        .map(|x| format!("{x:016x}"))
+}
+
+const FNV_OFFSET: u64 = 0xcbf29ce484222325;
+
+/// Folds the bytes into a 64-bit FNV-1a hash, which is stable across builds unlike
+/// the hasher of the standard library.
+fn fnv1a(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
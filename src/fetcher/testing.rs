@@ -0,0 +1,75 @@
+//! Helpers for testing the fetchers against local repositories.
+
+use std::path::Path;
+use std::process::Command;
+
+use indicatif::ProgressBar;
+
+use crate::config::{FetcherConfig, Mirrors};
+use crate::progress::Log;
+
+/// Identity and defaults of the test repositories, independent of the user config.
+const CONFIG: &[&str] = &[
+    "-c",
+    "user.name=rimeka",
+    "-c",
+    "user.email=rimeka@localhost",
+    "-c",
+    "commit.gpgsign=false",
+    "-c",
+    "init.defaultBranch=main",
+];
+
+/// Runs a git command in the directory, returning its output, and panicking if it fails.
+pub fn git(dir: &Path, args: &[&str]) -> String {
+    let output = Command::new("git")
+        .current_dir(dir)
+        .args(CONFIG)
+        .args(args)
+        .output()
+        .expect("git should be installed");
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "git {args:?} failed: {stderr}");
+
+    String::from_utf8_lossy(&output.stdout).trim().to_string()
+}
+
+/// Creates a repository on the branch `main` with the files committed, returning the
+/// commit.
+pub fn repo(dir: &Path, files: &[(&str, &str)]) -> String {
+    std::fs::create_dir_all(dir).unwrap();
+    git(dir, &["init", "--quiet"]);
+    commit(dir, files)
+}
+
+/// Writes and commits the files, returning the commit.
+pub fn commit(dir: &Path, files: &[(&str, &str)]) -> String {
+    for (path, content) in files {
+        let path = dir.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, content).unwrap();
+    }
+
+    git(dir, &["add", "--all"]);
+    git(dir, &["commit", "--quiet", "--message", "update"]);
+    git(dir, &["rev-parse", "HEAD"])
+}
+
+/// Returns a config fetching repositories of GitHub from the directory, e.g. `foo/bar`
+/// from `{dir}/foo/bar`.
+pub fn config(dir: &Path) -> FetcherConfig {
+    let remote = format!("file://{}/", dir.display());
+    let rules = vec![("https://github.com/".to_string(), vec![remote])];
+
+    FetcherConfig {
+        mirrors: Mirrors::new(rules),
+        ..Default::default()
+    }
+}
+
+/// Returns a log keeping only the latest line, which is returned by the progress bar.
+pub fn log() -> (Log, ProgressBar) {
+    let bar = ProgressBar::hidden();
+    (Log::Bar(bar.clone()), bar)
+}
@@ -0,0 +1,58 @@
+use std::path::Path;
+use std::process::Command;
+
+use anyhow::Context;
+use owo_colors::OwoColorize;
+
+use crate::Result;
+
+pub struct Hook<'a> {
+    name: &'static str,
+    commands: &'a [String],
+    env: Vec<(&'static str, String)>,
+}
+
+impl<'a> Hook<'a> {
+    pub fn new(name: &'static str, commands: &'a [String], rime_dir: &Path) -> Self {
+        Self {
+            name,
+            commands,
+            env: vec![
+                ("RIMEKA_HOOK", name.to_string()),
+                ("RIMEKA_RIME_DIR", rime_dir.to_string_lossy().into_owned()),
+            ],
+        }
+    }
+
+    pub fn env(mut self, key: &'static str, value: impl Into<String>) -> Self {
+        self.env.push((key, value.into()));
+        self
+    }
+
+    pub fn run(self) -> Result {
+        for command in self.commands {
+            println!("- {} {}", "Hook:".cyan(), command);
+
+            shell(command)
+                .envs(self.env.iter().map(|(k, v)| (k, v)))
+                .spawn()?
+                .wait()?
+                .exit_ok()
+                .with_context(|| format!("{} hook failed: {}", self.name, command))?;
+        }
+
+        Ok(())
+    }
+}
+
+fn shell(command: &str) -> Command {
+    if cfg!(target_os = "windows") {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
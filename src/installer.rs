@@ -1,4 +1,4 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs::File;
 use std::io::{BufReader, Read};
 use std::path::{Path, PathBuf};
@@ -10,8 +10,10 @@ use pathdiff::diff_paths;
 use saphyr::{Hash, Yaml, YamlEmitter};
 use walkdir::WalkDir;
 
+use crate::fetcher;
 use crate::glob::PatternSet;
 use crate::package::Package;
+use crate::progress::Log;
 use crate::spec::Recipe;
 use crate::Result;
 
@@ -34,14 +36,16 @@ impl<'a> RecipeInstaller<'a> {
     }
 
     pub fn install(mut self) -> Result {
-        let path = self.package.dir().join(self.recipe.filename()).clean();
-        let yaml = std::fs::read_to_string(&path).context("failed to read file")?;
-        let docs = Yaml::load_from_str(&yaml).context("failed to parse yaml")?;
-
+        let docs = self.load()?;
         let doc = &docs[0];
 
         self.resolve_options(&doc["recipe"]);
 
+        if let Some(files) = doc["download_files"].as_str() {
+            self.download_files(files)
+                .context("failed to download files")?;
+        }
+
         if let Some(patterns) = doc["install_files"].as_str() {
             self.install_files(patterns)
                 .context("failed to install files")?;
@@ -55,6 +59,34 @@ impl<'a> RecipeInstaller<'a> {
         Ok(())
     }
 
+    /// Downloads the `download_files` of the recipe without installing anything.
+    pub fn download(&self) -> Result {
+        let docs = self.load()?;
+        match docs[0]["download_files"].as_str() {
+            Some(files) => self
+                .download_files(files)
+                .context("failed to download files"),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the patterns of the files that the recipe installs.
+    pub fn patterns(&self) -> Result<(Vec<String>, Vec<String>)> {
+        let docs = self.load()?;
+        let include = match docs[0]["install_files"].as_str() {
+            Some(patterns) => shlex::split(patterns).context("syntax error in the file list")?,
+            None => Vec::new(),
+        };
+
+        Ok((include, Vec::new()))
+    }
+
+    fn load(&self) -> Result<Vec<Yaml>> {
+        let path = self.package.recipe_path(&self.recipe);
+        let yaml = std::fs::read_to_string(&path).context("failed to read file")?;
+        Yaml::load_from_str(&yaml).context("failed to parse yaml")
+    }
+
     fn resolve_options(&mut self, meta: &Yaml) {
         // Default options defined in the YAML
         if let Some(args) = meta["args"].as_vec() {
@@ -70,6 +102,52 @@ impl<'a> RecipeInstaller<'a> {
         self.options.extend(self.package.spec().options().clone());
     }
 
+    /// Downloads `path::url` entries, or plain URLs saved by their last segments, into
+    /// the package directory, where `install_files` can pick them up. Files already
+    /// downloaded from the same URL at the same revision of the package are kept.
+    fn download_files(&self, files: &str) -> Result {
+        let dir = self.package.dir();
+        let revision = self.package.commit().ok();
+        let mut downloads = read_downloads(dir);
+        self.package.ignore(fetcher::DOWNLOADS_FILE)?;
+
+        for file in shlex::split(files).context("syntax error in the file list")? {
+            let (path, url) = match file.split_once("::") {
+                Some((path, url)) => (path, url),
+                None => (file.rsplit('/').next().unwrap_or_default(), file.as_str()),
+            };
+
+            let dest = dir.join(path).clean();
+            if path.is_empty() || !dest.starts_with(dir) {
+                bail!("invalid download path: {path}");
+            }
+
+            self.package.ignore(path)?;
+            let record = revision.as_ref().map(|x| format!("{url}\t{x}"));
+            if dest.is_file() && record.is_some() && downloads.get(path) == record.as_ref() {
+                println!("- {} {} (already downloaded)", "Cached:".cyan(), path);
+                continue;
+            }
+
+            println!("- {} {}", "Downloading:".cyan(), path);
+            let urls = self.package.mirrors().urls(url);
+            match fetcher::download(&Log::Stdout, &urls, self.package.network(), &dest) {
+                Ok(()) => match record {
+                    Some(record) => downloads.insert(path.to_string(), record),
+                    None => downloads.remove(path),
+                },
+                // Keep the previously downloaded file, e.g. when installing offline
+                Err(e) if dest.is_file() => {
+                    println!("- {} {} ({:#})", "Using cached:".yellow(), path, e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+        }
+
+        write_downloads(dir, &downloads)
+    }
+
     fn install_files(&self, patterns: &str) -> Result {
         install_dir(
             self.package.dir(),
@@ -151,22 +229,27 @@ impl<'a> DefaultInstaller<'a> {
     }
 
     pub fn install(self) -> Result {
-        install_dir(
-            self.package.dir(),
-            &self.dest,
-            &["*.yaml", "*.txt", "*.gram", "opencc/*.*"],
-            &[
-                "recipe.yaml",
-                "**/*.recipe.yaml",
-                "**/*.custom.yaml",
-                "**/*.json",
-                "**/*.ocd",
-                "**/*.txt",
-            ],
-        )
+        install_dir(self.package.dir(), &self.dest, INCLUDE, EXCLUDE)
+    }
+
+    /// Returns the patterns of the files that are installed without a recipe.
+    pub fn patterns() -> (Vec<String>, Vec<String>) {
+        let owned = |patterns: &[&str]| patterns.iter().map(|x| x.to_string()).collect();
+        (owned(INCLUDE), owned(EXCLUDE))
     }
 }
 
+const INCLUDE: &[&str] = &["*.yaml", "*.txt", "*.gram", "opencc/*.*"];
+
+const EXCLUDE: &[&str] = &[
+    "recipe.yaml",
+    "**/*.recipe.yaml",
+    "**/*.custom.yaml",
+    "**/*.json",
+    "**/*.ocd",
+    "**/*.txt",
+];
+
 fn install_dir<P>(src: &Path, dest: &Path, include: &[P], exclude: &[P]) -> Result
 where
     P: AsRef<str>,
@@ -188,6 +271,14 @@ where
             continue;
         }
 
+        if is_lfs_pointer(from)? {
+            println!(
+                "- {} {} is a Git LFS pointer, not the content of the file",
+                "Warning:".yellow(),
+                relative.display()
+            );
+        }
+
         println!("- {} {}", "Copying:".cyan(), relative.display());
 
         std::fs::create_dir_all(to.parent().unwrap())?;
@@ -197,6 +288,18 @@ where
     Ok(())
 }
 
+/// Returns whether the file is a pointer left in place of a file stored in Git LFS.
+fn is_lfs_pointer(path: &Path) -> Result<bool> {
+    const SIGNATURE: &[u8] = b"version https://git-lfs.github.com/spec/v1";
+
+    // Pointer files are always smaller than 1024 bytes
+    if std::fs::metadata(path)?.len() >= 1024 {
+        return Ok(false);
+    }
+
+    Ok(std::fs::read(path)?.starts_with(SIGNATURE))
+}
+
 fn is_identical(from: &Path, to: &Path) -> Result<bool> {
     let size1 = std::fs::metadata(from)?.len();
     let size2 = std::fs::metadata(to).map(|x| x.len());
@@ -223,3 +326,22 @@ fn is_identical(from: &Path, to: &Path) -> Result<bool> {
 
     Ok(true)
 }
+
+/// Reads the URL and the revision of each downloaded file, by path.
+fn read_downloads(dir: &Path) -> BTreeMap<String, String> {
+    let content = std::fs::read_to_string(dir.join(fetcher::DOWNLOADS_FILE)).unwrap_or_default();
+    content
+        .lines()
+        .filter_map(|x| x.split_once('\t'))
+        .map(|(path, record)| (path.to_string(), record.to_string()))
+        .collect()
+}
+
+fn write_downloads(dir: &Path, downloads: &BTreeMap<String, String>) -> Result {
+    let content = downloads
+        .iter()
+        .map(|(path, record)| format!("{path}\t{record}\n"))
+        .collect::<String>();
+
+    Ok(std::fs::write(dir.join(fetcher::DOWNLOADS_FILE), content)?)
+}
@@ -2,6 +2,8 @@
 #![feature(exit_status_error)]
 #![feature(cfg_match)]
 
+use std::process::ExitCode;
+
 use owo_colors::OwoColorize;
 
 use crate::app::App;
@@ -10,21 +12,29 @@ use crate::spec::Spec;
 
 mod app;
 mod builtins;
+mod bundle;
+mod config;
 mod fetcher;
 mod glob;
+mod hooks;
 mod installer;
 mod options;
 mod package;
+mod progress;
 mod spec;
+mod state;
 
 pub type Result<T = (), E = anyhow::Error> = std::result::Result<T, E>;
 
-fn main() {
+fn main() -> ExitCode {
     let options = Options::parse();
     let app = App::new(options);
 
-    if let Err(e) = app.run() {
-        eprintln!("{} {:?}", "Error:".red().bold(), e);
-        std::process::exit(128);
+    match app.run() {
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("{} {:?}", "Error:".red().bold(), e);
+            ExitCode::from(128)
+        }
     }
 }
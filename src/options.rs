@@ -5,6 +5,8 @@ use std::str::FromStr;
 use anyhow::bail;
 use bpaf::{Args, Bpaf, ParseFailure};
 
+use crate::fetcher::LocalChanges;
+
 #[derive(Debug, Bpaf)]
 #[bpaf(options, version, fallback_to_usage)]
 pub struct Options {
@@ -24,18 +26,126 @@ pub struct Options {
     #[bpaf(short, long)]
     pub dir: Option<PathBuf>,
 
+    /// Specify the config file
+    #[bpaf(short, long)]
+    pub config: Option<PathBuf>,
+
+    /// Specify the number of repositories fetched concurrently
+    #[bpaf(short, long, fallback(4))]
+    pub jobs: usize,
+
+    /// Install from the cached packages without fetching them
+    #[bpaf(long)]
+    pub offline: bool,
+
+    /// Specify what to do with local changes in cached packages: refuse, stash,
+    /// rebase or discard
+    #[bpaf(long, argument("MODE"))]
+    pub local_changes: Option<LocalChanges>,
+
+    /// Install packages not in the trusted sources of the config file
+    #[bpaf(long)]
+    pub allow_untrusted: bool,
+
+    #[bpaf(external(command), optional)]
+    pub command: Option<Command>,
+
     /// Specify packages or recipes to be installed
     #[bpaf(positional("targets"))]
     pub targets: Vec<String>,
 }
 
+#[derive(Debug, Clone, Bpaf)]
+pub enum Command {
+    /// Update all packages installed into the RIME directory
+    #[bpaf(command)]
+    Update,
+
+    /// Check the cached packages for upstream changes, exiting with 100 if any
+    #[bpaf(command)]
+    Outdated,
+
+    /// Roll a package or recipe back to its previously installed revision
+    #[bpaf(command)]
+    Rollback {
+        /// Specify the package or recipe to be rolled back
+        #[bpaf(positional("target"))]
+        target: String,
+    },
+
+    /// Manage the cached packages
+    #[bpaf(command)]
+    Cache {
+        #[bpaf(external(cache_command))]
+        command: CacheCommand,
+    },
+
+    /// Create or install bundles of packages for machines without network access
+    #[bpaf(command)]
+    Bundle {
+        #[bpaf(external(bundle_command))]
+        command: BundleCommand,
+    },
+}
+
+#[derive(Debug, Clone, Bpaf)]
+pub enum CacheCommand {
+    /// Show the disk usage of each cached package
+    #[bpaf(command)]
+    Size,
+
+    /// Remove the cached packages not installed into any RIME directory
+    #[bpaf(command)]
+    Prune {
+        /// Show what would be removed without removing it
+        #[bpaf(short('n'), long)]
+        dry_run: bool,
+    },
+
+    /// Remove all the cached packages
+    #[bpaf(command)]
+    Clear {
+        /// Show what would be removed without removing it
+        #[bpaf(short('n'), long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Debug, Clone, Bpaf)]
+pub enum BundleCommand {
+    /// Fetch packages or recipes and write them into a bundle
+    #[bpaf(command)]
+    Create {
+        /// Specify the bundle file to be written
+        #[bpaf(
+            short,
+            long,
+            argument("FILE"),
+            fallback(PathBuf::from("rimeka-bundle.tar.gz"))
+        )]
+        output: PathBuf,
+
+        /// Specify packages or recipes to be bundled
+        #[bpaf(positional("targets"), some("a package or recipe is required"))]
+        targets: Vec<String>,
+    },
+
+    /// Install the packages in a bundle without network access
+    #[bpaf(command)]
+    Install {
+        /// Specify the bundle file
+        #[bpaf(positional("file"))]
+        file: PathBuf,
+    },
+}
+
 impl Options {
     pub fn parse() -> Self {
         let parser = options();
 
         match parser.run_inner(Args::current_args()) {
             Ok(mut options) => {
-                if options.targets.is_empty() {
+                if options.command.is_none() && options.targets.is_empty() {
                     options.targets.push(":preset".to_string());
                 }
 
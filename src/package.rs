@@ -2,40 +2,73 @@ use std::path::{Path, PathBuf};
 
 use anyhow::Context;
 use owo_colors::OwoColorize;
-use path_clean::PathClean;
 
-use crate::fetcher::GitHubFetcher;
+use crate::config::{FetcherConfig, Mirrors, Network, Signatures};
+use crate::fetcher::{self, Changes, Fetcher};
+use crate::glob::PatternSet;
 use crate::installer::{DefaultInstaller, RecipeInstaller};
+use crate::progress::Log;
 use crate::spec::{Recipe, Spec};
 use crate::Result;
 
 #[derive(Debug)]
 pub struct Package<'a> {
     spec: &'a Spec,
-    dir: PathBuf,
+    base: PathBuf,
+    fetcher: Box<dyn Fetcher>,
+    mirrors: Mirrors,
+    network: Network,
 }
 
 impl<'a> Package<'a> {
-    pub fn new(spec: &'a Spec, base: &Path) -> Self {
-        let dir = base.join(spec.repo()).clean();
-        Self { spec, dir }
+    pub fn new(spec: &'a Spec, base: &Path, config: &FetcherConfig) -> Result<Self> {
+        let fetcher = fetcher::select(spec, base, config)?;
+        let mirrors = config.mirrors.clone();
+        Ok(Self {
+            spec,
+            base: base.to_path_buf(),
+            fetcher,
+            mirrors,
+            network: config.network.clone(),
+        })
     }
 
     pub fn spec(&self) -> &Spec {
         self.spec
     }
 
-    pub fn dir(&self) -> &PathBuf {
-        &self.dir
+    pub fn dir(&self) -> &Path {
+        self.fetcher.dir()
     }
 
-    pub fn fetch(&self) -> Result {
-        let fetcher = GitHubFetcher::new(self.spec, &self.dir);
-        if self.dir.join(".git").is_dir() {
-            fetcher.pull()
-        } else {
-            fetcher.clone()
-        }
+    /// Returns the URL the package is fetched from, after applying the mirrors.
+    pub fn url(&self) -> Option<String> {
+        self.fetcher.url()
+    }
+
+    pub fn mirrors(&self) -> &Mirrors {
+        &self.mirrors
+    }
+
+    pub fn network(&self) -> &Network {
+        &self.network
+    }
+
+    pub fn fetch(&self, log: &Log) -> Result {
+        self.fetcher.fetch(log)
+    }
+
+    pub fn checkout(&self, commit: &str) -> Result {
+        self.fetcher.checkout(commit)
+    }
+
+    pub fn commit(&self) -> Result<String> {
+        self.fetcher.head()
+    }
+
+    /// Returns the latest upstream commit of the package.
+    pub fn remote_commit(&self) -> Result<String> {
+        self.fetcher.remote_head()
     }
 
     pub fn install(&self, dest: PathBuf) -> Result {
@@ -43,16 +76,78 @@ impl<'a> Package<'a> {
             println!("- {} {} = {}", "Option:".cyan(), k, v);
         }
 
-        if let Some(recipe) = self.spec.recipe() {
-            let installer = RecipeInstaller::new(self, dest, recipe.clone());
+        if let Some(recipe) = self.recipe() {
+            let installer = RecipeInstaller::new(self, dest, recipe);
             return installer.install().context("failed to install recipe");
         }
 
-        if self.dir.join(Recipe::Implicit.filename()).exists() {
-            let installer = RecipeInstaller::new(self, dest, Recipe::Implicit);
-            return installer.install().context("failed to install recipe");
+        DefaultInstaller::new(self, dest).install()
+    }
+
+    /// Returns the changes between two commits, with only the files to be installed.
+    pub fn changes(&self, from: &str, to: &str) -> Result<Option<Changes>> {
+        let Some(mut changes) = self.fetcher.changes(from, to)? else {
+            return Ok(None);
+        };
+
+        let (include, exclude) = self.patterns()?;
+        let include = PatternSet::new(&include)?;
+        let exclude = PatternSet::new(&exclude)?;
+        changes.files.retain(|x| {
+            let path = Path::new(x);
+            include.matches(path) && !exclude.matches(path)
+        });
+
+        Ok(Some(changes))
+    }
+
+    /// Downloads the `download_files` of the recipe into the package directory.
+    pub fn download(&self) -> Result {
+        match self.recipe() {
+            Some(recipe) => RecipeInstaller::new(self, PathBuf::new(), recipe).download(),
+            None => Ok(()),
         }
+    }
 
-        DefaultInstaller::new(self, dest).install()
+    /// Keeps a file downloaded into the package directory from being taken for a local
+    /// change.
+    pub fn ignore(&self, path: &str) -> Result {
+        self.fetcher.ignore(path)
+    }
+
+    /// Narrows the fetched files to those matching the install patterns, if the fetcher
+    /// supports sparse checkouts.
+    pub fn narrow(&self, log: &Log, patterns: &[String]) -> Result {
+        self.fetcher.narrow(log, patterns)
+    }
+
+    /// Verifies the signature of the fetched commit, or of a tag pointing to it.
+    pub fn verify(&self, signatures: &Signatures) -> Result {
+        self.fetcher.verify(signatures)
+    }
+
+    /// Returns the patterns of the files to be installed, and of those excluded.
+    pub fn patterns(&self) -> Result<(Vec<String>, Vec<String>)> {
+        match self.recipe() {
+            Some(recipe) => RecipeInstaller::new(self, PathBuf::new(), recipe).patterns(),
+            None => Ok(DefaultInstaller::patterns()),
+        }
+    }
+
+    /// Returns the path of the recipe file, which may be outside the package directory.
+    pub fn recipe_path(&self, recipe: &Recipe) -> PathBuf {
+        recipe.path(self.dir(), &self.base)
+    }
+
+    fn recipe(&self) -> Option<Recipe> {
+        if let Some(recipe) = self.spec.recipe() {
+            return Some(recipe.clone());
+        }
+
+        if self.dir().join(Recipe::Implicit.filename()).exists() {
+            return Some(Recipe::Implicit);
+        }
+
+        None
     }
 }
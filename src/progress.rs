@@ -0,0 +1,206 @@
+use std::fmt::Display;
+use std::io::{IsTerminal, Read};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use owo_colors::OwoColorize;
+
+use crate::Result;
+
+/// Destination of the output of a task.
+#[derive(Debug, Clone)]
+pub enum Log {
+    /// Prints to stdout as is
+    Stdout,
+    /// Prints lines prefixed by the name of the task, for non-interactive output
+    Prefixed(String),
+    /// Shows the latest line in a progress bar
+    Bar(ProgressBar),
+}
+
+impl Log {
+    pub fn println(&self, line: impl Display) {
+        let line = line.to_string();
+
+        match self {
+            Log::Stdout => println!("{line}"),
+            Log::Prefixed(_) | Log::Bar(_) if line.trim().is_empty() => {}
+            Log::Prefixed(prefix) => println!("[{prefix}] {line}"),
+            Log::Bar(bar) => bar.set_message(line.trim().to_string()),
+        }
+    }
+
+    /// Runs a command, with its output written to the terminal directly if the log is
+    /// stdout, or captured line by line otherwise. The command is killed after the
+    /// timeout if any.
+    pub fn run(&self, command: &mut Command, timeout: Option<Duration>) -> Result {
+        let program = command.get_program().to_string_lossy().into_owned();
+
+        // The error output is always captured to report the cause of failures
+        if !matches!(self, Log::Stdout) {
+            command.stdin(Stdio::null()).stdout(Stdio::piped());
+        }
+        let mut child = command.stderr(Stdio::piped()).spawn()?;
+
+        // Not scoped, as the output may be held open by descendants of a killed command
+        let stdout = child.stdout.take().map(|output| {
+            let log = self.clone();
+            std::thread::spawn(move || log.forward(output))
+        });
+        let stderr = child.stderr.take().context("stderr is not captured")?;
+        let stderr = {
+            let log = self.clone();
+            std::thread::spawn(move || log.forward(stderr))
+        };
+
+        let status = wait(&mut child, timeout).with_context(|| format!("`{program}` failed"))?;
+        if let Some(stdout) = stdout {
+            stdout.join().ok();
+        }
+        let error = stderr.join().unwrap_or_default();
+
+        status
+            .exit_ok()
+            .with_context(|| format!("`{program}` failed: {error}"))?;
+
+        Ok(())
+    }
+
+    /// Forwards the lines of the output, which may be separated by carriage returns
+    /// for progress, returning the line most likely telling the cause of a failure.
+    fn forward(&self, mut output: impl Read) -> String {
+        let mut error = None;
+        let mut last = String::new();
+        let mut buffer = Vec::new();
+        let mut chunk = [0; 1024];
+
+        while let Ok(n @ 1..) = output.read(&mut chunk) {
+            buffer.extend_from_slice(&chunk[..n]);
+
+            while let Some(i) = buffer.iter().position(|x| matches!(x, b'\r' | b'\n')) {
+                let line = String::from_utf8_lossy(&buffer[..i]).trim().to_string();
+                buffer.drain(..=i);
+
+                if !line.is_empty() {
+                    self.println(&line);
+                    if error.is_none() && is_error(&line) {
+                        error = Some(line.clone());
+                    }
+                    last = line;
+                }
+            }
+        }
+
+        let line = String::from_utf8_lossy(&buffer).trim().to_string();
+        if !line.is_empty() {
+            self.println(&line);
+            if error.is_none() && is_error(&line) {
+                error = Some(line.clone());
+            }
+            last = line;
+        }
+
+        error.unwrap_or(last)
+    }
+}
+
+/// Returns the line of the output most likely telling the cause of a failure, i.e. the
+/// first error, or the last line if none looks like an error.
+pub fn summary(output: &str) -> &str {
+    let mut lines = output.lines().map(str::trim).filter(|x| !x.is_empty());
+    lines
+        .clone()
+        .find(|x| is_error(x))
+        .or_else(|| lines.next_back())
+        .unwrap_or_default()
+}
+
+/// Returns whether the line is an error message, e.g. `fatal: ...` of git.
+fn is_error(line: &str) -> bool {
+    ["fatal:", "error:"].iter().any(|x| line.starts_with(x))
+}
+
+/// Waits for a child process to exit, killing it after the timeout if any.
+pub fn wait(child: &mut Child, timeout: Option<Duration>) -> Result<ExitStatus> {
+    let Some(timeout) = timeout else {
+        return Ok(child.wait()?);
+    };
+
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    child.kill()?;
+    child.wait()?;
+    bail!("timed out after {}s", timeout.as_secs())
+}
+
+/// Multi-line progress display of concurrent tasks, falling back to plain logs when
+/// stdout is not a terminal.
+pub struct Progress {
+    multi: Option<MultiProgress>,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            multi: std::io::stdout().is_terminal().then(MultiProgress::new),
+        }
+    }
+
+    pub fn task(&self, name: &str) -> Task {
+        let Some(multi) = &self.multi else {
+            return Task {
+                name: name.to_string(),
+                log: Log::Prefixed(name.to_string()),
+            };
+        };
+
+        let style = ProgressStyle::with_template("{spinner:.green} {prefix:.bold} {wide_msg:.dim}")
+            .expect("progress template should be valid");
+
+        let bar = multi.add(ProgressBar::new_spinner());
+        bar.set_style(style);
+        bar.set_prefix(name.to_string());
+        bar.enable_steady_tick(Duration::from_millis(100));
+
+        Task {
+            name: name.to_string(),
+            log: Log::Bar(bar),
+        }
+    }
+}
+
+pub struct Task {
+    name: String,
+    log: Log,
+}
+
+impl Task {
+    pub fn log(&self) -> &Log {
+        &self.log
+    }
+
+    pub fn finish(&self, ok: bool) {
+        let status = match ok {
+            true => "done".green().to_string(),
+            false => "failed".red().to_string(),
+        };
+
+        match &self.log {
+            Log::Bar(bar) => {
+                let style = ProgressStyle::with_template("{prefix:.bold} {wide_msg}")
+                    .expect("progress template should be valid");
+                bar.set_style(style);
+                bar.finish_with_message(status);
+            }
+            _ => println!("[{}] {}", self.name, status),
+        }
+    }
+}
@@ -1,18 +1,29 @@
 use std::collections::HashMap;
 use std::convert::Infallible;
 use std::fmt::Display;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
 use anyhow::{anyhow, bail};
 use owo_colors::OwoColorize;
+use path_clean::PathClean;
 
+use crate::config::FetcherConfig;
 use crate::package::Package;
 use crate::Result;
 
+/// Directory in the packages directory where recipe files are downloaded from URLs.
+pub const RECIPES_DIR: &str = ".recipes";
+
+const RECIPE_SUFFIX: &str = ".recipe.yaml";
+
 #[derive(Debug, Clone)]
 pub struct Spec {
+    /// The repository, or the directory containing a recipe file if the target is one
     repo: String,
+    origin: Origin,
+    /// Whether the repository is fetched over SSH, e.g. a private one
+    ssh: bool,
     branch: Option<String>,
     recipe: Option<Recipe>,
     options: HashMap<String, String>,
@@ -28,14 +39,71 @@ impl Spec {
         }
     }
 
+    /// Returns the host of the repository. Only GitHub is supported by the targets,
+    /// except for recipe files downloaded from URLs.
+    pub fn host(&self) -> &str {
+        match self.origin {
+            Origin::Repository => "github.com",
+            Origin::Directory => "localhost",
+            Origin::Url => split_url(&self.repo).map(|x| x.0).unwrap_or_default(),
+        }
+    }
+
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+
     pub fn repo(&self) -> &str {
         &self.repo
     }
 
+    pub fn is_ssh(&self) -> bool {
+        self.ssh
+    }
+
+    /// Returns the URL of the git repository, or of the directory containing the recipe
+    /// file, before applying the mirrors.
+    pub fn url(&self) -> String {
+        match (self.origin, self.ssh) {
+            (Origin::Repository, true) => format!("git@{}:{}", self.host(), self.repo),
+            (Origin::Repository, false) => format!("https://{}/{}", self.host(), self.repo),
+            _ => self.repo.clone(),
+        }
+    }
+
     pub fn branch(&self) -> Option<&str> {
         self.branch.as_deref()
     }
 
+    /// Returns the repository with the branch if specified, e.g. `foo/bar@dev`. Each
+    /// branch has its own checkout of the repository.
+    pub fn checkout(&self) -> String {
+        match &self.branch {
+            Some(branch) => format!("{}@{}", self.repo, branch),
+            None => self.repo.clone(),
+        }
+    }
+
+    /// Returns the path of the checkout relative to the packages directory, with the
+    /// slashes in the branch escaped to keep it next to the default branch.
+    pub fn checkout_path(&self) -> PathBuf {
+        match self.origin {
+            Origin::Repository => {}
+            Origin::Directory => return PathBuf::from(&self.repo),
+            Origin::Url => return recipes_path(&self.repo),
+        }
+
+        let checkout = match &self.branch {
+            Some(branch) => {
+                let branch = branch.replace('%', "%25").replace('/', "%2F");
+                format!("{}@{}", self.repo, branch)
+            }
+            None => self.repo.clone(),
+        };
+
+        PathBuf::from(checkout)
+    }
+
     pub fn recipe(&self) -> Option<&Recipe> {
         self.recipe.as_ref()
     }
@@ -47,10 +115,69 @@ impl Spec {
     pub fn name(&self) -> String {
         match &self.recipe {
             Some(Recipe::Explicit(name)) => format!("{}:{}", self.repo, name),
+            Some(Recipe::File(file)) if self.origin != Origin::Repository => file.clone(),
+            Some(Recipe::File(file)) => format!("{}:{}", self.repo, file),
             _ => self.repo.to_string(),
         }
     }
 
+    /// Returns the checkout with the recipe, e.g. `foo/bar@dev:baz`, which identifies an
+    /// installation as each branch is installed from its own checkout.
+    pub fn install_key(&self) -> String {
+        match &self.recipe {
+            Some(Recipe::Explicit(name)) => format!("{}:{}", self.checkout(), name),
+            Some(Recipe::File(file)) if self.origin != Origin::Repository => file.clone(),
+            Some(Recipe::File(file)) => format!("{}:{}", self.checkout(), file),
+            _ => self.checkout(),
+        }
+    }
+
+    pub fn options_string(&self) -> String {
+        let mut options = self
+            .options
+            .iter()
+            .map(|(k, v)| format!("{k}={v}"))
+            .collect::<Vec<_>>();
+
+        options.sort();
+        options.join(",")
+    }
+
+    /// Formats the spec back into a target that parses to the same spec.
+    pub fn target(&self) -> String {
+        let mut target = match (self.origin, self.ssh) {
+            (Origin::Repository, true) => format!("git@{}:{}", self.host(), self.repo),
+            (Origin::Repository, false) => self.repo.clone(),
+            _ => self.name(),
+        };
+
+        if self.origin != Origin::Repository {
+            if !self.options.is_empty() {
+                target.push(':');
+                target.push_str(&self.options_string());
+            }
+
+            return target;
+        }
+
+        if let Some(branch) = &self.branch {
+            target.push('@');
+            target.push_str(branch);
+        }
+
+        if let Some(recipe) = &self.recipe {
+            target.push(':');
+            target.push_str(&recipe.name());
+
+            if !self.options.is_empty() {
+                target.push(':');
+                target.push_str(&self.options_string());
+            }
+        }
+
+        target
+    }
+
     pub fn patch_id(&self) -> String {
         if self.recipe.is_none() {
             return String::new();
@@ -66,8 +193,22 @@ impl Spec {
         format!("{}:{}", self.name(), options)
     }
 
-    pub fn locate_package(&self, base: &Path) -> Package {
-        Package::new(self, base)
+    /// Moves a recipe file onto the repository declared as its source, against which
+    /// the files it installs are resolved.
+    pub fn with_source(self, source: Spec) -> Result<Spec> {
+        if source.origin != Origin::Repository || source.recipe.is_some() {
+            bail!("`source` must be a repository, e.g. foo/bar@dev");
+        }
+
+        Ok(Spec {
+            recipe: self.recipe,
+            options: self.options,
+            ..source
+        })
+    }
+
+    pub fn locate_package(&self, base: &Path, config: &FetcherConfig) -> Result<Package<'_>> {
+        Package::new(self, base, config)
     }
 }
 
@@ -104,10 +245,19 @@ impl FromStr for Spec {
             item.separated_by(just('/')).at_least(1).to_slice()
         }
 
-        pub fn repo<'s>() -> impl Parser<'s, &'s str, String, Extra<'s>> {
-            let community = username().then_ignore(just('/')).then(reponame());
+        fn community<'s>() -> impl Parser<'s, &'s str, String, Extra<'s>> {
+            let repo = username().then_ignore(just('/')).then(reponame());
+            repo.to_slice().map(|x| x.to_string())
+        }
+
+        /// Parses the repository, and whether it is fetched over SSH, e.g. with the
+        /// SSH clone URL `git@github.com:foo/bar`.
+        pub fn repo<'s>() -> impl Parser<'s, &'s str, (bool, String), Extra<'s>> {
+            let ssh = choice((just("git@github.com:"), just("ssh://git@github.com/")));
+            let ssh = ssh.ignore_then(community()).map(|x| (true, x));
             let builtins = reponame().map(|x| format!("rime/rime-{x}"));
-            community.to_slice().map(|x| x.to_string()).or(builtins)
+            let https = community().or(builtins).map(|x| (false, x));
+            ssh.or(https)
         }
 
         pub fn branch<'s>() -> impl Parser<'s, &'s str, Option<&'s str>, Extra<'s>> {
@@ -127,47 +277,83 @@ impl FromStr for Spec {
             just(':').ignore_then(list).or(default)
         }
 
-        pub fn parser<'s>() -> impl Parser<
-            's,
-            &'s str,
-            (
-                String,
-                Option<&'s str>,
-                Option<&'s str>,
-                HashMap<String, String>,
-            ),
-            Extra<'s>,
-        > {
+        /// Whether over SSH, the repository, the branch, the recipe and the options.
+        type Parts<'s> = (
+            (bool, String),
+            Option<&'s str>,
+            Option<&'s str>,
+            HashMap<String, String>,
+        );
+
+        pub fn parser<'s>() -> impl Parser<'s, &'s str, Parts<'s>, Extra<'s>> {
             group((repo(), branch(), recipe(), options())).then_ignore(end())
         }
 
+        /// Points out the first error in the part of the target starting at the offset.
+        fn error(target: &str, offset: usize, e: Vec<Rich<'_, char>>) -> anyhow::Error {
+            let span = e[0].span();
+            let before = &target[..offset + span.start];
+            let errored = &target[offset + span.start..offset + span.end];
+            let after = &target[offset + span.end..];
+
+            anyhow!(
+                "invalid package or recipe\n{}{}{}\n{}{}\n{}",
+                // expr
+                before,
+                errored.red().bold(),
+                after,
+                // pointer
+                " ".repeat(before.len()),
+                "^".repeat(errored.len()).red().bold(),
+                // message
+                e[0],
+            )
+        }
+
         if target.ends_with("-packages.conf") || target.ends_with("-packages.bat") {
             bail!("*-packages.conf and *-packages.bat are not supported")
         }
 
-        let (repo, branch, recipe, options) =
-            parser().parse(target.trim()).into_result().map_err(|e| {
-                let span = e[0].span();
-                let before = &target[..span.start];
-                let errored = &target[span.start..span.end];
-                let after = &target[span.end..];
-
-                anyhow!(
-                    "invalid package or recipe\n{}{}{}\n{}{}\n{}",
-                    // expr
-                    before,
-                    errored.red().bold(),
-                    after,
-                    // pointer
-                    " ".repeat(before.len()),
-                    "^".repeat(errored.len()).red().bold(),
-                    // message
-                    e[0],
-                )
-            })?;
+        let target = target.trim();
+
+        if let Some((repository, file, rest)) = split_file(target) {
+            let offset = target.len() - rest.len();
+            let options = options()
+                .then_ignore(end())
+                .parse(rest)
+                .into_result()
+                .map_err(|e| error(target, offset, e))?;
+
+            let file = locate(file)?;
+            let Some(repository) = repository else {
+                return Self::standalone(file, options);
+            };
+
+            let ((ssh, repo), branch) = group((repo(), branch()))
+                .then_ignore(end())
+                .parse(repository)
+                .into_result()
+                .map_err(|e| error(target, 0, e))?;
+
+            return Ok(Self {
+                repo,
+                origin: Origin::Repository,
+                ssh,
+                branch: branch.map(|x| x.to_string()),
+                recipe: Some(Recipe::File(file)),
+                options,
+            });
+        }
+
+        let ((ssh, repo), branch, recipe, options) = parser()
+            .parse(target)
+            .into_result()
+            .map_err(|e| error(target, 0, e))?;
 
         Ok(Self {
             repo,
+            origin: Origin::Repository,
+            ssh,
             branch: branch.map(|x| x.to_string()),
             recipe: recipe.map(|x| x.parse().unwrap()),
             options,
@@ -175,17 +361,81 @@ impl FromStr for Spec {
     }
 }
 
+impl Spec {
+    /// Creates the spec of a recipe file outside any repository, whose files are
+    /// resolved against the directory containing it.
+    fn standalone(file: String, options: HashMap<String, String>) -> Result<Self> {
+        let (origin, repo) = match is_url(&file) {
+            true => {
+                let (dir, _) = file.rsplit_once('/').unwrap_or_default();
+                if split_url(dir).is_none() {
+                    bail!("invalid URL of recipe file: {file}");
+                }
+                (Origin::Url, dir.to_string())
+            }
+            false => {
+                let dir = Path::new(&file).parent().unwrap_or(Path::new(""));
+                (Origin::Directory, dir.to_string_lossy().into_owned())
+            }
+        };
+
+        Ok(Self {
+            repo,
+            origin,
+            ssh: false,
+            branch: None,
+            recipe: Some(Recipe::File(file)),
+            options,
+        })
+    }
+}
+
+/// Where the files installed by a spec are resolved against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// A repository on GitHub
+    Repository,
+    /// The directory containing a recipe file on the local disk
+    Directory,
+    /// The URL of the directory containing a recipe file, e.g. of a gist
+    Url,
+}
+
 #[derive(Debug, Clone)]
 pub enum Recipe {
     Implicit,
     Explicit(String),
+    /// The URL or the absolute path of a recipe file outside the repository
+    File(String),
 }
 
 impl Recipe {
+    pub fn name(&self) -> String {
+        match self {
+            Recipe::Implicit => "recipe.yaml".to_string(),
+            Recipe::Explicit(name) => name.clone(),
+            Recipe::File(file) => file.clone(),
+        }
+    }
+
     pub fn filename(&self) -> String {
         match self {
             Recipe::Implicit => "recipe.yaml".to_string(),
             Recipe::Explicit(name) => format!("{name}.recipe.yaml"),
+            Recipe::File(file) => file.rsplit(['/', '\\']).next().unwrap_or(file).to_string(),
+        }
+    }
+
+    /// Returns the path of the recipe file. Recipe files of URLs are downloaded into
+    /// the packages directory `base`.
+    pub fn path(&self, dir: &Path, base: &Path) -> PathBuf {
+        match self {
+            Recipe::File(file) if is_url(file) => {
+                let (url, _) = file.rsplit_once('/').unwrap_or_default();
+                base.join(recipes_path(url)).join(self.filename())
+            }
+            Recipe::File(file) => PathBuf::from(file),
+            _ => dir.join(self.filename()).clean(),
         }
     }
 }
@@ -204,3 +454,211 @@ impl FromStr for Recipe {
         }
     }
 }
+
+/// Splits a target of a recipe file outside the repository into the repository if
+/// declared, the URL or path of the file, and the options, e.g.
+/// `foo/bar@dev:https://example.com/baz.recipe.yaml:key=value`.
+fn split_file(target: &str) -> Option<(Option<&str>, &str, &str)> {
+    let end = target.rfind(RECIPE_SUFFIX)? + RECIPE_SUFFIX.len();
+    let (location, rest) = target.split_at(end);
+    if !rest.is_empty() && !rest.starts_with(':') {
+        return None;
+    }
+
+    if is_file(location) || !location.contains(':') {
+        return Some((None, location, rest));
+    }
+
+    // Recipes in the repository, e.g. `foo/bar:baz.recipe.yaml`, are left to the parser
+    let (i, _) = location
+        .match_indices(':')
+        .find(|(i, _)| is_file(&location[i + 1..]))?;
+
+    Some((Some(&location[..i]), &location[i + 1..], rest))
+}
+
+/// Whether the recipe is a URL, or a path explicitly relative or absolute.
+fn is_file(recipe: &str) -> bool {
+    is_url(recipe)
+        || recipe.starts_with("./")
+        || recipe.starts_with("../")
+        || Path::new(recipe).is_absolute()
+}
+
+fn is_url(file: &str) -> bool {
+    file.starts_with("https://") || file.starts_with("http://")
+}
+
+/// Returns the URL as is, or the absolute path of the file.
+fn locate(file: &str) -> Result<String> {
+    if is_url(file) {
+        return Ok(file.to_string());
+    }
+
+    let path = std::path::absolute(file)?.clean();
+    Ok(path.to_string_lossy().into_owned())
+}
+
+/// Splits a URL into the host and the path, without the leading slash, which is empty
+/// at the root of the host.
+pub fn split_url(url: &str) -> Option<(&str, &str)> {
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))?;
+
+    match url.split_once('/').unwrap_or((url, "")) {
+        ("", _) => None,
+        x => Some(x),
+    }
+}
+
+/// Returns the path relative to the packages directory of a checkout returned by
+/// [`Spec::checkout`], or `None` if it is not fetched into the packages directory.
+pub fn checkout_path(checkout: &str) -> Option<PathBuf> {
+    if is_url(checkout) {
+        return Some(recipes_path(checkout));
+    }
+
+    let spec = checkout.parse::<Spec>().ok()?;
+    (spec.origin == Origin::Repository).then(|| spec.checkout_path())
+}
+
+/// Returns the path relative to the packages directory where the recipe files of the
+/// URL directory are downloaded, escaped into a single component.
+fn recipes_path(url: &str) -> PathBuf {
+    let url = url
+        .strip_prefix("https://")
+        .or_else(|| url.strip_prefix("http://"))
+        .unwrap_or(url);
+    let escaped = url
+        .replace('%', "%25")
+        .replace('/', "%2F")
+        .replace(':', "%3A");
+
+    Path::new(RECIPES_DIR).join(escaped)
+}
+
+/// Reverses the escaping of the branch in the path of a checkout, or of the URL in the
+/// path of the recipe files.
+pub fn unescape_path(path: &str) -> String {
+    path.replace("%2F", "/")
+        .replace("%3A", ":")
+        .replace("%25", "%")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split_file_of_url() {
+        let url = "https://example.com/baz.recipe.yaml";
+        assert_eq!(split_file(url), Some((None, url, "")));
+        assert_eq!(
+            split_file("https://example.com/baz.recipe.yaml:key=value"),
+            Some((None, url, ":key=value"))
+        );
+    }
+
+    #[test]
+    fn split_file_with_repository() {
+        assert_eq!(
+            split_file("foo/bar@dev:https://example.com/baz.recipe.yaml:key=value"),
+            Some((
+                Some("foo/bar@dev"),
+                "https://example.com/baz.recipe.yaml",
+                ":key=value"
+            ))
+        );
+        assert_eq!(
+            split_file("foo/bar:./baz.recipe.yaml"),
+            Some((Some("foo/bar"), "./baz.recipe.yaml", ""))
+        );
+    }
+
+    #[test]
+    fn split_file_leaves_recipes_in_repository() {
+        assert_eq!(split_file("foo/bar:baz.recipe.yaml"), None);
+        assert_eq!(split_file("foo/bar:baz"), None);
+        assert_eq!(split_file("./baz.recipe.yaml.bak"), None);
+    }
+
+    #[test]
+    fn standalone_url() {
+        let spec = "https://example.com/gist/baz.recipe.yaml:key=value"
+            .parse::<Spec>()
+            .unwrap();
+        assert_eq!(spec.origin(), Origin::Url);
+        assert_eq!(spec.repo(), "https://example.com/gist");
+        assert_eq!(spec.host(), "example.com");
+        assert_eq!(spec.name(), "https://example.com/gist/baz.recipe.yaml");
+        assert_eq!(spec.options_string(), "key=value");
+    }
+
+    #[test]
+    fn standalone_url_at_root() {
+        let spec = "http://example.com/baz.recipe.yaml"
+            .parse::<Spec>()
+            .unwrap();
+        assert_eq!(spec.repo(), "http://example.com");
+        assert_eq!(spec.host(), "example.com");
+        assert_eq!(spec.checkout_path(), Path::new(".recipes/example.com"));
+    }
+
+    #[test]
+    fn standalone_url_without_host() {
+        assert!("https:///baz.recipe.yaml".parse::<Spec>().is_err());
+    }
+
+    #[test]
+    fn standalone_path() {
+        let spec = "/tmp/foo/baz.recipe.yaml".parse::<Spec>().unwrap();
+        assert_eq!(spec.origin(), Origin::Directory);
+        assert_eq!(spec.repo(), "/tmp/foo");
+        assert_eq!(spec.checkout_path(), Path::new("/tmp/foo"));
+    }
+
+    #[test]
+    fn split_url_into_host_and_path() {
+        let split = split_url;
+        assert_eq!(
+            split("https://example.com/a/b"),
+            Some(("example.com", "a/b"))
+        );
+        assert_eq!(split("http://example.com"), Some(("example.com", "")));
+        assert_eq!(split("https://"), None);
+        assert_eq!(split("ftp://example.com/a"), None);
+    }
+
+    #[test]
+    fn unescape_checkout_path() {
+        let spec = "foo/bar@feature/x".parse::<Spec>().unwrap();
+        let path = spec.checkout_path();
+        assert_eq!(path, Path::new("foo/bar@feature%2Fx"));
+        assert_eq!(unescape_path(&path.to_string_lossy()), spec.checkout());
+    }
+
+    #[test]
+    fn checkout_path_of_checkout() {
+        let spec = "foo/bar@feature/x".parse::<Spec>().unwrap();
+        assert_eq!(checkout_path(&spec.checkout()), Some(spec.checkout_path()));
+
+        let spec = "https://example.com/a/baz.recipe.yaml"
+            .parse::<Spec>()
+            .unwrap();
+        assert_eq!(checkout_path(&spec.checkout()), Some(spec.checkout_path()));
+        assert_eq!(checkout_path("/tmp/foo"), None);
+    }
+
+    #[test]
+    fn unescape_recipes_path() {
+        let spec = "https://127.0.0.1:8765/a%2Fb/baz.recipe.yaml"
+            .parse::<Spec>()
+            .unwrap();
+        let path = spec.checkout_path();
+        assert_eq!(path, Path::new(".recipes/127.0.0.1%3A8765%2Fa%252Fb"));
+
+        let name = path.file_name().unwrap().to_string_lossy();
+        assert_eq!(unescape_path(&name), "127.0.0.1:8765/a%2Fb");
+    }
+}
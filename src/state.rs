@@ -0,0 +1,216 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context};
+use saphyr::{Hash, Yaml, YamlEmitter};
+
+use crate::spec::Spec;
+use crate::Result;
+
+/// Records of the packages installed into each RIME directory, and of the fetched
+/// repositories.
+#[derive(Debug, Default)]
+pub struct State {
+    path: PathBuf,
+    installs: Vec<Install>,
+    /// Last fetch time of each repository, in seconds since the Unix epoch
+    fetched: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Install {
+    pub rime_dir: PathBuf,
+    pub target: String,
+    pub commit: String,
+    /// Previously installed commits, the most recent first.
+    pub history: Vec<String>,
+}
+
+impl State {
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut state = Self {
+            path: path.to_path_buf(),
+            ..Default::default()
+        };
+
+        if !path.exists() {
+            return Ok(state);
+        }
+
+        let yaml = std::fs::read_to_string(path).context("failed to read state file")?;
+        let docs = Yaml::load_from_str(&yaml).context("failed to parse state file")?;
+
+        let Some(doc) = docs.first() else {
+            return Ok(state);
+        };
+
+        for install in doc["installs"].as_vec().into_iter().flatten() {
+            state
+                .installs
+                .push(Install::parse(install).context("corrupted state file")?);
+        }
+
+        for (repo, time) in doc["fetched"].as_hash().into_iter().flatten() {
+            let (Some(repo), Some(time)) = (repo.as_str(), time.as_i64()) else {
+                bail!("corrupted state file");
+            };
+            state.fetched.insert(repo.to_string(), time.try_into()?);
+        }
+
+        Ok(state)
+    }
+
+    pub fn save(&self) -> Result {
+        let installs = self.installs.iter().map(Install::to_yaml).collect();
+
+        let fetched = self
+            .fetched
+            .iter()
+            .map(|(repo, time)| (Yaml::String(repo.clone()), Yaml::Integer(*time as i64)))
+            .collect();
+
+        let mut doc = Hash::new();
+        doc.insert(Yaml::from_str("installs"), Yaml::Array(installs));
+        doc.insert(Yaml::from_str("fetched"), Yaml::Hash(fetched));
+
+        let mut out = String::new();
+        let mut emitter = YamlEmitter::new(&mut out);
+        emitter.dump(&Yaml::Hash(doc))?;
+
+        std::fs::write(&self.path, out + "\n").context("failed to write state file")
+    }
+
+    pub fn installs(&self) -> impl Iterator<Item = &Install> {
+        self.installs.iter()
+    }
+
+    pub fn installs_in<'a>(&'a self, rime_dir: &'a Path) -> impl Iterator<Item = &'a Install> {
+        self.installs.iter().filter(move |x| x.rime_dir == rime_dir)
+    }
+
+    pub fn find(&self, rime_dir: &Path, spec: &Spec) -> Option<&Install> {
+        self.installs
+            .iter()
+            .find(|x| x.rime_dir == rime_dir && x.is(spec))
+    }
+
+    /// Records an installation, replacing the previous one of the same package or recipe
+    /// on the same branch.
+    /// Up to `limit` previously installed commits are kept for rolling back.
+    pub fn record(&mut self, rime_dir: &Path, spec: &Spec, commit: String, limit: usize) {
+        let Some(install) = self.find_mut(rime_dir, spec) else {
+            self.installs.push(Install {
+                rime_dir: rime_dir.to_path_buf(),
+                target: spec.target(),
+                commit,
+                history: Vec::new(),
+            });
+            return;
+        };
+
+        install.target = spec.target();
+
+        if install.commit != commit {
+            let previous = std::mem::replace(&mut install.commit, commit);
+            install
+                .history
+                .retain(|x| x != &previous && x != &install.commit);
+            install.history.insert(0, previous);
+            install.history.truncate(limit);
+        }
+    }
+
+    /// Moves the installation back to the most recent commit in its history.
+    pub fn rollback(&mut self, rime_dir: &Path, spec: &Spec) -> Option<String> {
+        let install = self.find_mut(rime_dir, spec)?;
+        if install.history.is_empty() {
+            return None;
+        }
+
+        install.commit = install.history.remove(0);
+        Some(install.commit.clone())
+    }
+
+    /// Returns how long ago the repository was last fetched.
+    pub fn fetched_since(&self, repo: &str) -> Option<Duration> {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(*self.fetched.get(repo)?);
+        time.elapsed().ok()
+    }
+
+    /// Returns the fetched repositories, as given to [`State::mark_fetched`].
+    pub fn fetched(&self) -> impl Iterator<Item = &str> {
+        self.fetched.keys().map(String::as_str)
+    }
+
+    pub fn mark_fetched(&mut self, repo: &str) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        self.fetched.insert(repo.to_string(), now.as_secs());
+    }
+
+    /// Forgets the last fetch time of a removed repository.
+    pub fn unmark_fetched(&mut self, repo: &str) {
+        self.fetched.remove(repo);
+    }
+
+    fn find_mut(&mut self, rime_dir: &Path, spec: &Spec) -> Option<&mut Install> {
+        self.installs
+            .iter_mut()
+            .find(|x| x.rime_dir == rime_dir && x.is(spec))
+    }
+}
+
+impl Install {
+    pub fn spec(&self) -> Result<Spec> {
+        self.target.parse()
+    }
+
+    fn is(&self, spec: &Spec) -> bool {
+        self.spec()
+            .is_ok_and(|x| x.install_key() == spec.install_key())
+    }
+
+    fn parse(yaml: &Yaml) -> Result<Self> {
+        let field = |key: &str| {
+            yaml[key]
+                .as_str()
+                .map(String::from)
+                .with_context(|| format!("missing `{key}` in install record"))
+        };
+
+        Ok(Self {
+            rime_dir: PathBuf::from(field("rime_dir")?),
+            target: field("target")?,
+            commit: field("commit")?,
+            history: yaml["history"]
+                .as_vec()
+                .into_iter()
+                .flatten()
+                .filter_map(|x| x.as_str())
+                .map(String::from)
+                .collect(),
+        })
+    }
+
+    fn to_yaml(&self) -> Yaml {
+        let mut hash = Hash::new();
+        let mut field = |key: &str, value: &str| {
+            hash.insert(Yaml::from_str(key), Yaml::String(value.to_string()));
+        };
+
+        field("rime_dir", &self.rime_dir.to_string_lossy());
+        field("target", &self.target);
+        field("commit", &self.commit);
+
+        let history = self
+            .history
+            .iter()
+            .map(|x| Yaml::String(x.clone()))
+            .collect();
+        hash.insert(Yaml::from_str("history"), Yaml::Array(history));
+
+        Yaml::Hash(hash)
+    }
+}